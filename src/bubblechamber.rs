@@ -1,48 +1,195 @@
 use amethyst::core::math::Vector3;
+use amethyst::ecs::Entity;
 use amethyst::input::is_key_down;
 use amethyst::input::VirtualKeyCode;
 use amethyst::prelude::*;
+use amethyst::ui::{get_default_font, Anchor, FontAsset, UiText, UiTransform};
+use amethyst::window::ScreenDimensions;
+use amethyst::winit::{ElementState, Event, LogicalPosition, MouseButton, WindowEvent};
 
 use amethyst::{
     assets::{AssetStorage, Handle, Loader},
     core::{Hidden, Transform},
     renderer::{
-        transparent::Transparent, Camera, ImageFormat, SpriteRender, SpriteSheet,
-        SpriteSheetFormat, Texture,
+        palette::Srgba, resources::Tint, transparent::Transparent, Camera, ImageFormat,
+        SpriteRender, SpriteSheet, SpriteSheetFormat, Texture,
     },
 };
 
+use log::info;
 use rand::distributions::{Distribution, Exp};
-use rand::thread_rng;
-use svg::node::element::path::Data;
-use svg::node::element::{Path, Rectangle};
-use svg::Document;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 
 use crate::components::{LifeTime, Particle, Trace, Velocity};
-use crate::config::{ChamberConfig, MagneticFieldConfig, MultiParticlesConfig};
-use crate::resources::{MagneticField, SVGBuilder};
+use crate::config::{
+    ChamberConfig, ElectricFieldConfig, FieldGridConfig, HeadlessConfig, MagneticFieldConfig,
+    MultiParticlesConfig, NoiseConfig, OutputConfig,
+};
+use crate::export::{
+    output_genealogy, output_plots, output_png, output_svg, output_tracks, FrameRecorder,
+    GifRecorder,
+};
+use crate::field::{FieldMap, FieldSource};
+use crate::geometry::Shape;
+use crate::resources::{
+    BackgroundNoise, DecayVertices, ElectricField, FieldAdjustment, MagneticField, ParticleLog,
+    SVGBuilder, SimulationControl, SimulationTime,
+};
+
+/// Each press of the speed up or slow down keys multiplies or divides the speed by this
+const SPEED_STEP: f32 = 2.0;
+/// The simulation can't go faster than this, or slower than one over this
+const MAX_SPEED: f32 = 64.0;
+/// Each press of the field keys makes the magnetic field this many times stronger or weaker
+const FIELD_STEP: f32 = 1.25;
+/// Each press of the tilt keys turns the magnetic field this many degrees
+const TILT_STEP: f32 = 15.0;
+
+pub struct BubbleChamber {
+    /// Set when running without a window
+    headless: Option<HeadlessConfig>,
+    frames: Option<FrameRecorder>,
+    gif: Option<GifRecorder>,
+    sprite_sheet: Option<Handle<SpriteSheet>>,
+    /// Where the mouse is, in the chamber
+    cursor: Option<[f32; 2]>,
+    /// Where the particle being fired with the mouse starts, and its charges
+    drag_start: Option<([f32; 2], [usize; 3])>,
+    /// The text showing the magnetic field
+    field_overlay: Option<Entity>,
+}
+
+impl BubbleChamber {
+    pub fn new(headless: Option<HeadlessConfig>) -> BubbleChamber {
+        BubbleChamber {
+            headless,
+            frames: None,
+            gif: None,
+            sprite_sheet: None,
+            cursor: None,
+            drag_start: None,
+            field_overlay: None,
+        }
+    }
 
-pub struct BubbleChamber;
+    /// Click to place a particle, and drag to give it a velocity.
+    /// The modifier keys held when clicking pick its charges.
+    fn handle_mouse(&mut self, world: &mut World, event: &Event) {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return,
+        };
+
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Some(window_to_chamber(world, *position));
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                modifiers,
+                ..
+            } => {
+                let mouse = &world.read_resource::<MultiParticlesConfig>().mouse;
+                let charges = if modifiers.shift {
+                    mouse.shift
+                } else if modifiers.ctrl {
+                    mouse.ctrl
+                } else if modifiers.alt {
+                    mouse.alt
+                } else {
+                    mouse.charges
+                };
+
+                self.drag_start = self.cursor.map(|start| (start, charges));
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Released,
+                button: MouseButton::Left,
+                ..
+            } => {
+                let (start, charges, end) = match (self.drag_start.take(), self.cursor) {
+                    (Some((start, charges)), Some(end)) => (start, charges, end),
+                    _ => return,
+                };
+                let (speed, decay_rate) = {
+                    let config = world.read_resource::<MultiParticlesConfig>();
+                    (config.mouse.speed, config.decay_rate)
+                };
+
+                spawn_particle(
+                    world,
+                    charges,
+                    Vector3::new(start[0], start[1], 0.0),
+                    Vector3::new(end[0] - start[0], end[1] - start[1], 0.0) * speed,
+                    Exp::new(decay_rate as f64).sample(&mut thread_rng()) as f32,
+                    self.sprite_sheet.as_ref(),
+                );
+            }
+            _ => {}
+        }
+    }
+}
 
 impl SimpleState for BubbleChamber {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
         let world = data.world;
-        let sprite_sheet_handle = load_sprite_sheet(world);
+        // Without a window, there's nothing to draw sprites on
+        let sprite_sheet_handle = match self.headless {
+            Some(_) => None,
+            None => Some(load_sprite_sheet(world)),
+        };
 
-        initialise_particles(world, sprite_sheet_handle);
+        initialise_particles(world, sprite_sheet_handle.clone());
+        initialise_noise(world, sprite_sheet_handle.clone());
         initialise_magnetic_field(world);
-        initialise_camera(world);
+        initialise_electric_field(world);
+        if self.headless.is_none() {
+            initialise_camera(world);
+            self.field_overlay = Some(initialise_field_overlay(world));
+        }
         initialise_svg(world);
+
+        self.sprite_sheet = sprite_sheet_handle;
+        self.frames = FrameRecorder::new(world);
+        self.gif = GifRecorder::new(world);
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        if let Some(frames) = &mut self.frames {
+            frames.record(data.world);
+        }
+        if let Some(gif) = &mut self.gif {
+            gif.record(data.world);
+        }
+        if let Some(field_overlay) = self.field_overlay {
+            update_field_overlay(data.world, field_overlay);
+        }
+
+        if let Some(headless) = &self.headless {
+            if data.world.read_resource::<SimulationTime>().elapsed >= headless.duration {
+                return Trans::Quit;
+            }
+        }
+
+        Trans::None
     }
 
     fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-        // On stop, we construct the SVG:
+        // On stop, we construct the SVG, the PNG and the plots, and dump the raw tracks and decays:
         output_svg(data.world);
+        output_png(data.world);
+        output_plots(data.world);
+        output_tracks(data.world);
+        output_genealogy(data.world);
+        // Dropping the recorder finishes the GIF
+        self.gif = None;
     }
 
     fn handle_event(
         &mut self,
-        _data: StateData<'_, GameData<'_, '_>>,
+        data: StateData<'_, GameData<'_, '_>>,
         event: StateEvent,
     ) -> SimpleTrans {
         if let StateEvent::Window(event) = &event {
@@ -50,6 +197,10 @@ impl SimpleState for BubbleChamber {
                 // Pause the game by going to the `PausedState`.
                 return Trans::Pop;
             }
+
+            handle_simulation_keys(data.world, event);
+            handle_field_keys(data.world, event);
+            self.handle_mouse(data.world, event);
         }
 
         // Escape isn't pressed, so we stay in this `State`.
@@ -57,6 +208,88 @@ impl SimpleState for BubbleChamber {
     }
 }
 
+/// Space pauses and resumes, period steps while paused, plus and minus change the speed
+fn handle_simulation_keys(world: &World, event: &Event) {
+    let mut control = world.write_resource::<SimulationControl>();
+    if is_key_down(event, VirtualKeyCode::Space) {
+        control.paused = !control.paused;
+    } else if is_key_down(event, VirtualKeyCode::Period) {
+        control.step = true;
+    } else if is_key_down(event, VirtualKeyCode::Equals) || is_key_down(event, VirtualKeyCode::Add)
+    {
+        control.speed = (control.speed * SPEED_STEP).min(MAX_SPEED);
+        info!("Simulation speed: {}x", control.speed);
+    } else if is_key_down(event, VirtualKeyCode::Minus)
+        || is_key_down(event, VirtualKeyCode::Subtract)
+    {
+        control.speed = (control.speed / SPEED_STEP).max(1.0 / MAX_SPEED);
+        info!("Simulation speed: {}x", control.speed);
+    }
+}
+
+/// Up and down make the magnetic field stronger and weaker, F flips it, left and right tilt it
+/// and R undoes all of that
+fn handle_field_keys(world: &World, event: &Event) {
+    let mut magnetic_field = world.write_resource::<MagneticField>();
+    let adjustment = &mut magnetic_field.adjustment;
+
+    if is_key_down(event, VirtualKeyCode::Up) {
+        adjustment.strength *= FIELD_STEP;
+    } else if is_key_down(event, VirtualKeyCode::Down) {
+        adjustment.strength /= FIELD_STEP;
+    } else if is_key_down(event, VirtualKeyCode::F) {
+        adjustment.strength = -adjustment.strength;
+    } else if is_key_down(event, VirtualKeyCode::Left) {
+        adjustment.tilt -= TILT_STEP.to_radians();
+    } else if is_key_down(event, VirtualKeyCode::Right) {
+        adjustment.tilt += TILT_STEP.to_radians();
+    } else if is_key_down(event, VirtualKeyCode::R) {
+        *adjustment = FieldAdjustment::default();
+    }
+}
+
+/// A line of text in the top left corner of the window, filled in by `update_field_overlay`
+fn initialise_field_overlay(world: &mut World) -> Entity {
+    let font = get_default_font(
+        &world.read_resource::<Loader>(),
+        &world.read_resource::<AssetStorage<FontAsset>>(),
+    );
+    let transform = UiTransform::new(
+        String::from("magnetic_field"),
+        Anchor::TopLeft,
+        Anchor::TopLeft,
+        10.0,
+        -10.0,
+        1.0,
+        800.0,
+        24.0,
+    );
+    let mut text = UiText::new(font, String::new(), [1.0, 1.0, 1.0, 0.8], 18.0);
+    text.align = Anchor::MiddleLeft;
+
+    world.create_entity().with(transform).with(text).build()
+}
+
+/// Shows the magnetic field at the center of the chamber, and how it has been adjusted
+fn update_field_overlay(world: &World, field_overlay: Entity) {
+    let center = world.read_resource::<ChamberConfig>().outline().center();
+    let now = world.read_resource::<SimulationTime>().elapsed;
+    let magnetic_field = world.read_resource::<MagneticField>();
+    let field = magnetic_field.at(&Vector3::new(center[0], center[1], 0.0), now);
+    let adjustment = &magnetic_field.adjustment;
+
+    if let Some(text) = world.write_storage::<UiText>().get_mut(field_overlay) {
+        text.text = format!(
+            "B = ({:.2}, {:.2}, {:.2})   strength x{:.2}, tilted {:.0} degrees",
+            field[0],
+            field[1],
+            field[2],
+            adjustment.strength,
+            adjustment.tilt.to_degrees()
+        );
+    }
+}
+
 fn initialise_camera(world: &mut World) {
     let (chamber_width, chamber_height) = {
         let config = &world.read_resource::<ChamberConfig>();
@@ -73,68 +306,287 @@ fn initialise_camera(world: &mut World) {
         .build();
 }
 
-fn initialise_particles(world: &mut World, sprite_sheet: Handle<SpriteSheet>) {
+fn initialise_particles(world: &mut World, sprite_sheet: Option<Handle<SpriteSheet>>) {
+    let mut rng = thread_rng();
+
     let (decay_rate, particle_configs): (f32, Vec<([usize; 3], Vector3<f32>, Vector3<f32>)>) = {
         let config = &world.read_resource::<MultiParticlesConfig>();
+        let chamber = &world.read_resource::<ChamberConfig>();
+
+        let mut particle_configs: Vec<_> = config
+            .at_start
+            .iter()
+            .map(|conf| (conf.charges, conf.location, conf.velocity))
+            .collect();
+
+        for emitter in &config.emitters {
+            let region = emitter.region.clone().unwrap_or_else(|| chamber.outline());
+
+            for _ in 0..emitter.count {
+                let location = region.random_point(&mut rng);
+                let angle = rng.gen_range(0.0, 2.0 * std::f32::consts::PI);
+
+                particle_configs.push((
+                    emitter.charges,
+                    Vector3::new(location[0], location[1], 0.0),
+                    Vector3::new(angle.cos(), angle.sin(), 0.0) * emitter.speed,
+                ));
+            }
+        }
 
-        (
-            config.decay_rate,
-            config
-                .at_start
-                .iter()
-                .map(|conf| (conf.charges, conf.location, conf.velocity))
-                .collect(),
-        )
+        (config.decay_rate, particle_configs)
     };
-    let mut rng = thread_rng();
     let decay_distribution = Exp::new(decay_rate as f64);
 
     for (charges, location, velocity) in particle_configs {
-        let particle = Particle::new(charges);
-        let mut transform = Transform::default();
-        transform.set_translation_xyz(location[0], location[1], location[2]);
-        let velocity = Velocity { v: velocity };
+        spawn_particle(
+            world,
+            charges,
+            location,
+            velocity,
+            decay_distribution.sample(&mut rng) as f32,
+            sprite_sheet.as_ref(),
+        );
+    }
+}
 
+/// Creates a particle at `location`, flying at `velocity`, that decays after `decays_after`.
+/// It only gets a sprite if there's a sprite sheet to take it from.
+fn spawn_particle(
+    world: &mut World,
+    charges: [usize; 3],
+    location: Vector3<f32>,
+    velocity: Vector3<f32>,
+    decays_after: f32,
+    sprite_sheet: Option<&Handle<SpriteSheet>>,
+) {
+    let trace_neutrals = world.read_resource::<OutputConfig>().ghost_tracks.is_some();
+    let now = world.read_resource::<SimulationTime>().elapsed;
+
+    let particle = Particle::new(charges);
+    let mut transform = Transform::default();
+    transform.set_translation_xyz(location[0], location[1], location[2]);
+    let trace = Trace::new(
+        &particle,
+        [location[0], location[1]],
+        [velocity[0], velocity[1]],
+        now,
+    );
+    let velocity = Velocity { v: velocity };
+    let total_charge = particle.total_charge;
+
+    let mut entity = world
+        .create_entity()
+        .with(particle)
+        .with(LifeTime::new(decays_after))
+        .with(transform)
+        .with(velocity)
+        .with(Transparent);
+
+    if let Some(sprite_sheet) = sprite_sheet {
         // Assign the sprite for the particles
-        let sprite_render = SpriteRender {
+        entity = entity.with(SpriteRender {
             sprite_sheet: sprite_sheet.clone(),
             sprite_number: 0, // particle is the first and only sprite in the sprite_sheet
-        };
-        let total_charge = particle.total_charge;
+        });
+    }
 
-        let mut entity = world
+    if total_charge != 0 || trace_neutrals {
+        entity = entity.with(trace);
+    }
+
+    if total_charge == 0 {
+        // Neutral particles do not leave tracks
+        entity = entity.with(Hidden);
+    }
+
+    entity.build();
+}
+
+/// Where a cursor position in the window lands in the chamber, which the camera stretches
+/// over the whole window
+fn window_to_chamber(world: &World, position: LogicalPosition) -> [f32; 2] {
+    let screen = world.read_resource::<ScreenDimensions>();
+    let chamber = world.read_resource::<ChamberConfig>();
+    let physical = position.to_physical(screen.hidpi_factor());
+
+    [
+        physical.x as f32 / screen.width() * chamber.width,
+        (1.0 - physical.y as f32 / screen.height()) * chamber.height,
+    ]
+}
+
+fn initialise_magnetic_field(world: &mut World) {
+    let (field, variation) = {
+        let config = &world.read_resource::<MagneticFieldConfig>();
+        (
+            build_field_map(config.field, &config.sources, &config.grids),
+            config.variation.clone(),
+        )
+    };
+
+    world.insert(MagneticField {
+        field,
+        variation,
+        adjustment: FieldAdjustment::default(),
+    });
+}
+
+fn initialise_electric_field(world: &mut World) {
+    let field = {
+        let config = &world.read_resource::<ElectricFieldConfig>();
+        build_field_map(config.field, &config.sources, &config.grids)
+    };
+
+    world.insert(ElectricField { field });
+}
+
+fn build_field_map(
+    uniform: Vector3<f32>,
+    sources: &[FieldSource],
+    grid_configs: &[FieldGridConfig],
+) -> FieldMap {
+    let grids = grid_configs
+        .iter()
+        .map(|grid| {
+            grid.load()
+                .expect("Field grids are checked with the config")
+        })
+        .collect();
+
+    FieldMap {
+        uniform,
+        sources: sources.to_vec(),
+        grids,
+    }
+}
+
+/// Layers of haze stacked on top of each other, fading out towards the edge of a patch
+const HAZE_LAYERS: usize = 4;
+
+fn initialise_noise(world: &mut World, sprite_sheet: Option<Handle<SpriteSheet>>) {
+    let noise = {
+        let output = world.read_resource::<OutputConfig>();
+        let chamber = world.read_resource::<ChamberConfig>();
+
+        match &output.noise {
+            Some(config) => {
+                // Offset the seed, so the noise doesn't mirror the bubble placement in the output
+                let mut rng = StdRng::seed_from_u64(output.seed.wrapping_add(1));
+                generate_noise(config, &chamber.outline(), &mut rng)
+            }
+            None => BackgroundNoise::default(),
+        }
+    };
+
+    let sprite = match sprite_sheet {
+        Some(sprite_sheet) => SpriteRender {
+            sprite_sheet: sprite_sheet,
+            sprite_number: 0,
+        },
+        None => {
+            world.insert(noise);
+            return;
+        }
+    };
+    // The particle sprite is 8 pixels wide
+    let spawn_dot = |world: &mut World, center: [f32; 2], radius: f32, alpha: f32| {
+        let mut transform = Transform::default();
+        transform.set_translation_xyz(center[0], center[1], -0.5);
+        transform.set_scale(Vector3::new(radius / 4.0, radius / 4.0, 1.0));
+
+        world
             .create_entity()
-            .with(particle)
-            .with(LifeTime::new(decay_distribution.sample(&mut rng) as f32))
+            .with(sprite.clone())
             .with(transform)
-            .with(velocity)
-            .with(sprite_render.clone())
-            .with(Transparent);
+            .with(Tint(Srgba::new(1.0, 1.0, 1.0, alpha)))
+            .with(Transparent)
+            .build();
+    };
 
-        if total_charge != 0 {
-            entity = entity.with(Trace::new(location[0], location[1]));
+    for (center, radius, opacity) in &noise.haze {
+        spawn_dot(world, *center, *radius, *opacity);
+    }
+    for (center, radius) in &noise.bubbles {
+        spawn_dot(world, *center, *radius, 0.8);
+    }
+    for track in &noise.stray_tracks {
+        for segment in track.windows(2) {
+            let length = (segment[1][0] - segment[0][0]).hypot(segment[1][1] - segment[0][1]);
+            let dots = (length / 2.0).ceil() as usize;
+
+            for i in 0..dots {
+                let t = i as f32 / dots as f32;
+                let center = [
+                    segment[0][0] + (segment[1][0] - segment[0][0]) * t,
+                    segment[0][1] + (segment[1][1] - segment[0][1]) * t,
+                ];
+                spawn_dot(world, center, 0.75, 0.5);
+            }
         }
+    }
+
+    world.insert(noise);
+}
 
-        if total_charge == 0 {
-            // Neutral particles do not leave tracks
-            entity = entity.with(Hidden);
+fn generate_noise<R: Rng>(config: &NoiseConfig, region: &Shape, rng: &mut R) -> BackgroundNoise {
+    let mut noise = BackgroundNoise::default();
+
+    for _ in 0..config.haze_patches {
+        let center = region.random_point(rng);
+        let radius = config.haze_radius * rng.gen_range(0.3, 1.0);
+
+        // Smaller layers on top of bigger ones make the patch denser towards its core
+        for layer in 0..HAZE_LAYERS {
+            let scale = 1.0 - layer as f32 / HAZE_LAYERS as f32;
+            noise.haze.push((
+                center,
+                radius * scale,
+                config.haze_opacity / HAZE_LAYERS as f32,
+            ));
         }
+    }
 
-        entity.build();
+    for _ in 0..config.bubbles {
+        let (min_radius, max_radius) = config.bubble_radius;
+        let radius = if max_radius > min_radius {
+            rng.gen_range(min_radius, max_radius)
+        } else {
+            min_radius
+        };
+        noise.bubbles.push((region.random_point(rng), radius));
     }
-}
 
-fn initialise_magnetic_field(world: &mut World) {
-    let field = {
-        let config = &world.read_resource::<MagneticFieldConfig>();
-        config.field
-    };
+    for _ in 0..config.stray_tracks {
+        let steps = 8;
+        let length = config.stray_length * rng.gen_range(0.5, 1.5);
+        let step_length = length / steps as f32;
+        // Gently curving, like a slow particle in the magnetic field
+        let curvature = rng.gen_range(-2.0, 2.0) / length;
+
+        let mut point = region.random_point(rng);
+        let mut heading = rng.gen_range(0.0, 2.0 * std::f32::consts::PI);
+        let mut track = vec![point];
+
+        for _ in 0..steps {
+            point = [
+                point[0] + heading.cos() * step_length,
+                point[1] + heading.sin() * step_length,
+            ];
+            heading += curvature * step_length;
+            track.push(point);
+        }
+
+        noise.stray_tracks.push(track);
+    }
 
-    world.insert(MagneticField { field: field });
+    noise
 }
 
 fn initialise_svg(world: &mut World) {
     world.insert(SVGBuilder::default());
+    world.insert(ParticleLog::default());
+    world.insert(DecayVertices::default());
 }
 
 fn load_sprite_sheet(world: &mut World) -> Handle<SpriteSheet> {
@@ -161,40 +613,3 @@ fn load_sprite_sheet(world: &mut World) -> Handle<SpriteSheet> {
         &sprite_sheet_store,
     )
 }
-
-pub fn output_svg(world: &mut World) {
-    let svg_builder = world.read_resource::<SVGBuilder>();
-
-    let (viewbox_width, viewbox_height) = {
-        let config = world.read_resource::<ChamberConfig>();
-        (config.width, config.height)
-    };
-    let mut document = Document::new().set("viewBox", (0, 0, viewbox_width, viewbox_height));
-
-    // Add 100% rect in black as background
-    document = document.add(
-        Rectangle::new()
-            .set("width", "100%")
-            .set("height", "100%")
-            .set("fill", "black"),
-    );
-
-    for path in &svg_builder.paths {
-        // Starting point
-        let mut data = Data::new().move_to((path[0][0], path[0][1]));
-
-        // Make the curve
-        let all_points: Vec<f32> = path[1..].iter().flatten().cloned().collect();
-        data = data.cubic_curve_to(all_points);
-
-        let path = Path::new()
-            .set("fill", "none")
-            .set("stroke", "white")
-            .set("stroke-width", 3)
-            .set("d", data.to_owned());
-
-        document = document.add(path);
-    }
-
-    svg::save("particles.svg", &document).unwrap();
-}
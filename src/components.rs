@@ -1,5 +1,8 @@
 use amethyst::core::math::Vector3;
 use amethyst::ecs::prelude::{Component, DenseVecStorage, NullStorage, VecStorage};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_PARTICLE_ID: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone)]
 pub struct Velocity {
@@ -15,6 +18,14 @@ pub struct Particle {
     pub charges: [usize; 3],
     pub total_charge: isize,
     pub mass: usize,
+    /// Unique for every particle created during a run
+    pub id: usize,
+    /// The particle this one was produced by
+    pub parent: Option<usize>,
+    /// How many decays it took to produce this particle, 0 for the ones present at the start
+    pub generation: usize,
+    /// Knocked out of the medium by its parent, rather than produced by a decay
+    pub delta_ray: bool,
 }
 
 impl Particle {
@@ -29,8 +40,26 @@ impl Particle {
             charges: charges,
             total_charge: charges[0] as isize + (charges[2] as isize * -1),
             mass: mass,
+            id: NEXT_PARTICLE_ID.fetch_add(1, Ordering::Relaxed),
+            parent: None,
+            generation: 0,
+            delta_ray: false,
         }
     }
+
+    pub fn child_of(mut self, parent: &Particle) -> Particle {
+        self.parent = Some(parent.id);
+        self.generation = parent.generation + 1;
+        self
+    }
+
+    /// A delta ray knocked out by `parent`. It didn't take a decay, so it keeps its generation.
+    pub fn knocked_out_by(mut self, parent: &Particle) -> Particle {
+        self.parent = Some(parent.id);
+        self.generation = parent.generation;
+        self.delta_ray = true;
+        self
+    }
 }
 
 impl Component for Particle {
@@ -55,8 +84,17 @@ impl LifeTime {
     }
 }
 
+#[derive(Clone)]
 pub struct Trace {
+    pub charge: isize,
+    /// Id and generation of the particle that left the trace
+    pub particle: usize,
+    pub generation: usize,
     pub points: Vec<[f32; 2]>,
+    /// The particle's velocity at each of the points
+    pub velocities: Vec<[f32; 2]>,
+    /// Simulation time at each of the points
+    pub times: Vec<f32>,
 }
 
 impl Component for Trace {
@@ -64,11 +102,39 @@ impl Component for Trace {
 }
 
 impl Trace {
-    pub fn new(start_x: f32, start_y: f32) -> Trace {
+    pub fn new(particle: &Particle, start: [f32; 2], velocity: [f32; 2], time: f32) -> Trace {
+        Trace {
+            charge: particle.total_charge,
+            particle: particle.id,
+            generation: particle.generation,
+            points: vec![start],
+            velocities: vec![velocity],
+            times: vec![time],
+        }
+    }
+
+    /// A new trace for the same particle, starting over somewhere else
+    pub fn restart(&self, start: [f32; 2], velocity: [f32; 2], time: f32) -> Trace {
         Trace {
-            points: vec![[start_x, start_y]],
+            charge: self.charge,
+            particle: self.particle,
+            generation: self.generation,
+            points: vec![start],
+            velocities: vec![velocity],
+            times: vec![time],
         }
     }
+
+    pub fn push(&mut self, point: [f32; 2], velocity: [f32; 2], time: f32) {
+        self.points.push(point);
+        self.velocities.push(velocity);
+        self.times.push(time);
+    }
+
+    /// The particle's speed at point `i`
+    pub fn speed(&self, i: usize) -> f32 {
+        self.velocities[i][0].hypot(self.velocities[i][1])
+    }
 }
 
 #[derive(Default)]
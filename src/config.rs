@@ -1,17 +1,147 @@
+use amethyst::config::Config;
 use amethyst::core::math::Vector3;
+use amethyst::utils::application_root_dir;
 use serde::{Deserialize, Serialize};
 
+use crate::field::{FieldGrid, FieldGridData, FieldSource, TimeVariation};
+use crate::geometry::Shape;
+
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct SimulationConfig {
     pub chamber: ChamberConfig,
     pub magnetic_field: MagneticFieldConfig,
+    #[serde(default)]
+    pub electric_field: ElectricFieldConfig,
     pub particles: MultiParticlesConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub headless: Option<HeadlessConfig>,
+    #[serde(default)]
+    pub trail: TrailMode,
+}
+
+impl SimulationConfig {
+    /// Catches what would otherwise only show up as a panic deep inside the simulation
+    pub fn validate(&self) -> Result<(), String> {
+        let check_shape = |what: &str, shape: &Shape| {
+            shape
+                .validate()
+                .map_err(|e| format!("Invalid {}: {}", what, e))
+        };
+
+        check_shape("chamber outline", &self.chamber.outline())?;
+        for material in &self.chamber.materials {
+            check_shape("material shape", &material.shape)?;
+        }
+        for emitter in &self.particles.emitters {
+            if let Some(region) = &emitter.region {
+                check_shape("emitter region", region)?;
+            }
+        }
+
+        validate_field(
+            "magnetic field",
+            &self.magnetic_field.sources,
+            &self.magnetic_field.grids,
+        )?;
+        validate_field(
+            "electric field",
+            &self.electric_field.sources,
+            &self.electric_field.grids,
+        )?;
+        self.magnetic_field
+            .variation
+            .validate()
+            .map_err(|e| format!("Invalid magnetic field variation: {}", e))?;
+
+        // NaN isn't above 0 either
+        let check_positive = |what: &str, value: f32| {
+            if value > 0.0 {
+                Ok(())
+            } else {
+                Err(format!("{} must be above 0, not {}", what, value))
+            }
+        };
+
+        // A run that doesn't advance never reaches its duration
+        if let Some(headless) = &self.headless {
+            check_positive("The headless step", headless.step)?;
+        }
+        if let Some(frames) = &self.output.frames {
+            check_positive("The frame rate of the frame sequence", frames.frame_rate)?;
+            if let Some(fade) = frames.fade {
+                check_positive("The fade of the frame sequence", fade)?;
+            }
+        }
+        if let Some(gif) = &self.output.gif {
+            check_positive("The frame rate of the GIF", gif.frame_rate)?;
+            if let Some(fade) = gif.fade {
+                check_positive("The fade of the GIF", fade)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the sources of a field, and loads its grids to check them too
+fn validate_field(
+    what: &str,
+    sources: &[FieldSource],
+    grids: &[FieldGridConfig],
+) -> Result<(), String> {
+    for source in sources {
+        source
+            .validate()
+            .map_err(|e| format!("Invalid {} source: {}", what, e))?;
+    }
+    for grid in grids {
+        grid.load()
+            .map_err(|e| format!("Invalid {} grid: {}", what, e))?;
+    }
+
+    Ok(())
+}
+
+/// Runs the simulation without a window, as fast as possible, in fixed steps of `step` seconds.
+/// Stops once `duration` seconds have been simulated.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HeadlessConfig {
+    pub step: f32,
+    pub duration: f32,
+}
+
+/// How the trails particles leave behind show on screen
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum TrailMode {
+    /// Trails stay for as long as the simulation runs
+    Persistent,
+    /// Trails lose half of their opacity every `half_life` seconds, until they're gone,
+    /// like the tracks in a cloud chamber
+    Fading { half_life: f32 },
+}
+
+impl Default for TrailMode {
+    fn default() -> Self {
+        TrailMode::Persistent
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChamberConfig {
     pub width: f32,
     pub height: f32,
+    /// The outline of the chamber, a `width` by `height` rectangle if not set
+    #[serde(default)]
+    pub shape: Option<Shape>,
+    #[serde(default)]
+    pub boundary: BoundaryMode,
+    /// How far outside of the chamber the boundary lies
+    #[serde(default)]
+    pub margin: f32,
+    #[serde(default)]
+    pub materials: Vec<MaterialConfig>,
 }
 
 impl Default for ChamberConfig {
@@ -19,27 +149,161 @@ impl Default for ChamberConfig {
         ChamberConfig {
             width: 100.0,
             height: 100.0,
+            shape: None,
+            boundary: BoundaryMode::Open,
+            margin: 0.0,
+            materials: Vec::new(),
+        }
+    }
+}
+
+impl ChamberConfig {
+    pub fn outline(&self) -> Shape {
+        match &self.shape {
+            Some(shape) => shape.clone(),
+            None => Shape::Rectangle {
+                origin: [0.0, 0.0],
+                size: [self.width, self.height],
+            },
         }
     }
+
+    /// The density of the chamber contents at `point`, relative to the default medium.
+    /// Overlapping materials multiply their densities.
+    pub fn density_at(&self, point: [f32; 2]) -> f32 {
+        self.materials
+            .iter()
+            .filter(|material| material.shape.contains(point))
+            .map(|material| material.density)
+            .product()
+    }
+}
+
+/// A region of denser (or thinner) material, like an absorber plate.
+/// Particles inside it lose energy and age `density` times as fast.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MaterialConfig {
+    pub shape: Shape,
+    pub density: f32,
+    /// Fill colour of the region in the SVG, not drawn if not set
+    #[serde(default)]
+    pub fill: Option<String>,
+}
+
+/// What happens to particles crossing the chamber boundary
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum BoundaryMode {
+    /// Particles keep going forever
+    Open,
+    /// Particles are deleted and their trace is finalised
+    Absorb,
+    /// Particles bounce off the boundary
+    Reflect,
+    /// Particles re-enter on the opposite side, starting a new trace.
+    /// Rectangular chambers wrap around like a torus, other shapes send particles
+    /// back in through the point opposite of the chamber center.
+    Wrap,
+}
+
+impl Default for BoundaryMode {
+    fn default() -> Self {
+        BoundaryMode::Open
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MagneticFieldConfig {
     pub field: Vector3<f32>,
+    #[serde(default)]
+    pub sources: Vec<FieldSource>,
+    #[serde(default)]
+    pub grids: Vec<FieldGridConfig>,
+    #[serde(default)]
+    pub variation: TimeVariation,
 }
 
 impl Default for MagneticFieldConfig {
     fn default() -> Self {
         MagneticFieldConfig {
             field: Vector3::new(0.0, 0.0, 2.0),
+            sources: Vec::new(),
+            grids: Vec::new(),
+            variation: TimeVariation::Constant,
         }
     }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ElectricFieldConfig {
+    pub field: Vector3<f32>,
+    #[serde(default)]
+    pub sources: Vec<FieldSource>,
+    #[serde(default)]
+    pub grids: Vec<FieldGridConfig>,
+}
+
+impl Default for ElectricFieldConfig {
+    fn default() -> Self {
+        ElectricFieldConfig {
+            field: Vector3::zeros(),
+            sources: Vec::new(),
+            grids: Vec::new(),
+        }
+    }
+}
+
+/// A sampled field grid, loaded from a file relative to the config directory.
+/// `origin` is the chamber position of the first sample, `spacing` the distance between samples.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FieldGridConfig {
+    pub file: String,
+    pub origin: [f32; 2],
+    pub spacing: f32,
+}
+
+impl FieldGridConfig {
+    /// Reads the grid's file from the config directory and checks that it fits the grid
+    pub fn load(&self) -> Result<FieldGrid, String> {
+        if self.spacing <= 0.0 {
+            return Err(format!(
+                "{} needs a spacing above 0, not {}",
+                self.file, self.spacing
+            ));
+        }
+
+        let config_dir = application_root_dir()
+            .map_err(|e| format!("Failed to find the application root: {}", e))?
+            .join("config");
+        let data = FieldGridData::load_no_fallback(config_dir.join(&self.file))
+            .map_err(|e| format!("Failed to load {}: {}", self.file, e))?;
+
+        if data.values.len() != data.columns * data.rows {
+            return Err(format!(
+                "{} should have {} values, found {}",
+                self.file,
+                data.columns * data.rows,
+                data.values.len()
+            ));
+        }
+
+        Ok(FieldGrid {
+            origin: self.origin,
+            spacing: self.spacing,
+            data,
+        })
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MultiParticlesConfig {
     pub decay_rate: f32,
     pub at_start: Vec<ParticleConfig>,
+    #[serde(default)]
+    pub emitters: Vec<EmitterConfig>,
+    #[serde(default)]
+    pub delta_rays: Option<DeltaRayConfig>,
+    #[serde(default)]
+    pub mouse: MouseSpawnConfig,
 }
 
 impl Default for MultiParticlesConfig {
@@ -48,10 +312,60 @@ impl Default for MultiParticlesConfig {
         MultiParticlesConfig {
             decay_rate: 0.5,
             at_start: vec![particle],
+            emitters: Vec::new(),
+            delta_rays: None,
+            mouse: MouseSpawnConfig::default(),
+        }
+    }
+}
+
+/// Particles fired with the mouse. Dragging one unit gives them `speed`.
+/// They get `charges`, or the charges for the modifier key held when clicking.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MouseSpawnConfig {
+    pub speed: f32,
+    pub charges: [usize; 3],
+    pub shift: [usize; 3],
+    pub ctrl: [usize; 3],
+    pub alt: [usize; 3],
+}
+
+impl Default for MouseSpawnConfig {
+    fn default() -> Self {
+        MouseSpawnConfig {
+            speed: 5.0,
+            charges: [1, 0, 0],
+            shift: [0, 0, 1],
+            ctrl: [10, 20, 10],
+            alt: [1, 1, 1],
         }
     }
 }
 
+/// Spawns `count` particles at the start, at random locations inside `region`
+/// (the whole chamber if not set), flying in random directions at `speed`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmitterConfig {
+    pub count: usize,
+    pub charges: [usize; 3],
+    pub speed: f32,
+    #[serde(default)]
+    pub region: Option<Shape>,
+}
+
+/// Knock-on electrons kicked out along the tracks of charged particles.
+/// On average, `rate` electrons are produced per unit of charge per unit of distance travelled.
+/// They fly off sideways at a speed between `min_speed` and `max_speed`
+/// and disappear after `lifetime` seconds.
+/// The electrons themselves don't produce any.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeltaRayConfig {
+    pub rate: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub lifetime: f32,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ParticleConfig {
     pub charges: [usize; 3],
@@ -68,3 +382,311 @@ impl Default for ParticleConfig {
         }
     }
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OutputConfig {
+    /// Seed for everything random in the rendered output, like bubble placement
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub track_style: TrackStyle,
+    #[serde(default)]
+    pub colours: TrackColours,
+    #[serde(default)]
+    pub noise: Option<NoiseConfig>,
+    #[serde(default)]
+    pub png: Option<PngConfig>,
+    #[serde(default)]
+    pub print: Option<PrintConfig>,
+    #[serde(default)]
+    pub plotter: Option<PlotterConfig>,
+    /// Writes the raw data of every track to particles.jsonl or particles.csv if set
+    #[serde(default)]
+    pub track_data: Option<TrackDataFormat>,
+    /// Writes the decay tree of all particles to decays.json or decays.dot if set
+    #[serde(default)]
+    pub genealogy: Option<GenealogyFormat>,
+    #[serde(default)]
+    pub vertex_markers: Option<VertexMarkerConfig>,
+    #[serde(default)]
+    pub ghost_tracks: Option<GhostTrackConfig>,
+    #[serde(default)]
+    pub animation: Option<AnimationConfig>,
+    #[serde(default)]
+    pub frames: Option<FrameSequenceConfig>,
+    #[serde(default)]
+    pub gif: Option<GifConfig>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            seed: 0,
+            track_style: TrackStyle::default(),
+            colours: TrackColours::default(),
+            noise: None,
+            png: None,
+            print: None,
+            plotter: None,
+            track_data: None,
+            genealogy: None,
+            vertex_markers: None,
+            ghost_tracks: None,
+            animation: None,
+            frames: None,
+            gif: None,
+        }
+    }
+}
+
+/// Size in pixels of the rasterised PNG. The chamber is scaled to fit and centered.
+/// Ignored when printing: a PNG is then always written, sized by the print's DPI.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PngConfig {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Physical size of the output for prints, in `unit`s.
+/// The chamber is scaled to fit inside the `margin` and centered on the trimmed `width` by `height`.
+/// The black background extends past the trim by `bleed` on every side.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrintConfig {
+    pub unit: PrintUnit,
+    pub width: f32,
+    pub height: f32,
+    pub dpi: f32,
+    #[serde(default)]
+    pub bleed: f32,
+    #[serde(default)]
+    pub margin: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum PrintUnit {
+    Millimetres,
+    Inches,
+}
+
+impl PrintUnit {
+    pub fn per_inch(self) -> f32 {
+        match self {
+            PrintUnit::Millimetres => 25.4,
+            PrintUnit::Inches => 1.0,
+        }
+    }
+
+    /// The unit's suffix in SVG lengths
+    pub fn svg_suffix(self) -> &'static str {
+        match self {
+            PrintUnit::Millimetres => "mm",
+            PrintUnit::Inches => "in",
+        }
+    }
+}
+
+/// Random imperfections sprinkled over the chamber, on screen and in the SVG
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NoiseConfig {
+    /// Number of stray bubbles, with a radius between `bubble_radius.0` and `bubble_radius.1`
+    pub bubbles: usize,
+    pub bubble_radius: (f32, f32),
+    /// Number of short, faint stray tracks of about `stray_length` long
+    pub stray_tracks: usize,
+    pub stray_length: f32,
+    /// Number of haze patches of up to `haze_radius`, `haze_opacity` opaque at their core
+    pub haze_patches: usize,
+    pub haze_radius: f32,
+    pub haze_opacity: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum TrackStyle {
+    /// Continuous curves
+    Line { width: f32 },
+    /// Strings of discrete bubbles, placed at random. A singly charged particle moving at
+    /// `reference_speed` leaves a bubble every `spacing` on average. Slower and more highly
+    /// charged particles ionise more, so they leave denser strings.
+    /// The bubble radius varies randomly by up to `jitter` times `radius`.
+    Bubbles {
+        spacing: f32,
+        radius: f32,
+        jitter: f32,
+        reference_speed: f32,
+    },
+}
+
+impl Default for TrackStyle {
+    fn default() -> Self {
+        TrackStyle::Line { width: 3.0 }
+    }
+}
+
+/// CSS colours of the tracks of positively and negatively charged particles.
+/// If `by_generation` is set, tracks are coloured by their particle's generation instead:
+/// the particles present at the start get the first colour, their decay products the second,
+/// and so on, with the last colour for all later generations.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TrackColours {
+    pub positive: String,
+    pub negative: String,
+    #[serde(default)]
+    pub by_generation: Vec<String>,
+}
+
+impl Default for TrackColours {
+    fn default() -> Self {
+        TrackColours {
+            positive: String::from("white"),
+            negative: String::from("white"),
+            by_generation: Vec::new(),
+        }
+    }
+}
+
+impl TrackColours {
+    pub fn for_track(&self, charge: isize, generation: usize) -> &str {
+        if !self.by_generation.is_empty() {
+            &self.by_generation[generation.min(self.by_generation.len() - 1)]
+        } else if charge < 0 {
+            &self.negative
+        } else {
+            &self.positive
+        }
+    }
+}
+
+/// Markers drawn in the SVG where particles decayed, `size` across
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VertexMarkerConfig {
+    pub shape: MarkerShape,
+    pub size: f32,
+    pub colour: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum MarkerShape {
+    /// A filled circle
+    Dot,
+    /// A circle outline
+    Ring,
+    /// A diagonal cross
+    Cross,
+    /// A filled square standing on its corner
+    Diamond,
+}
+
+/// Records the paths of neutral particles, which don't leave a track, and draws them in the SVG
+/// as lines of `width` at `opacity`. They're dashed with dashes and gaps of `dash` if set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GhostTrackConfig {
+    pub colour: String,
+    pub width: f32,
+    pub opacity: f32,
+    #[serde(default)]
+    pub dash: Option<f32>,
+}
+
+/// Also writes particles_animated.svg, in which tracks draw themselves as they were simulated,
+/// played back `speed` times as fast. Line tracks are drawn as straight segments between their
+/// points in it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnimationConfig {
+    pub speed: f32,
+}
+
+/// Writes the chamber as it evolves to a numbered sequence of `width` by `height` PNGs in
+/// `directory`, `frame_rate` frames per simulated second. If `fade` is set, tracks lose half of
+/// their opacity every `fade` seconds after they were left.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FrameSequenceConfig {
+    pub directory: String,
+    pub frame_rate: f32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub fade: Option<f32>,
+}
+
+/// Writes the chamber as it evolves to an endlessly looping `width` by `height` particles.gif,
+/// `frame_rate` frames per simulated second. `fade` works like it does for frame sequences.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GifConfig {
+    pub frame_rate: f32,
+    pub width: u16,
+    pub height: u16,
+    #[serde(default)]
+    pub fade: Option<f32>,
+}
+
+/// JSON Lines gets a line for every trace, CSV a row for every point of every trace
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum TrackDataFormat {
+    JsonLines,
+    Csv,
+}
+
+/// JSON nests the decay products of each particle in it, DOT is a Graphviz digraph
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub enum GenealogyFormat {
+    Json,
+    Dot,
+}
+
+/// Pen plotter output. Tracks are drawn as lines whatever the track style, scaled to fit
+/// inside `margin` on a bed of `bed` millimetres and centered, the same way up as the SVG.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlotterConfig {
+    pub bed: [f32; 2],
+    #[serde(default)]
+    pub margin: f32,
+    /// `(colour, pen)` pairs: the pen to draw tracks of each colour with.
+    /// Tracks in a colour without a pen are drawn with pen 1.
+    #[serde(default)]
+    pub pens: Vec<(String, usize)>,
+    #[serde(default)]
+    pub optimise: Option<PathOrderConfig>,
+    /// Writes particles.hpgl if set
+    #[serde(default)]
+    pub hpgl: Option<HpglConfig>,
+    /// Writes particles.gcode if set
+    #[serde(default)]
+    pub gcode: Option<GCodeConfig>,
+}
+
+impl PlotterConfig {
+    pub fn pen_for(&self, colour: &str) -> usize {
+        self.pens
+            .iter()
+            .find(|(pen_colour, _)| pen_colour == colour)
+            .map_or(1, |(_, pen)| *pen)
+    }
+}
+
+/// Reorders the tracks drawn with each pen to cut down on travel with the pen up.
+/// With `reverse`, tracks may be drawn from end to start. Tracks ending within `merge_distance`
+/// millimetres of the start of the next one are drawn without lifting the pen.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PathOrderConfig {
+    pub reverse: bool,
+    #[serde(default)]
+    pub merge_distance: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HpglConfig {
+    /// Pen speed in cm/s, the plotter's own default if not set
+    #[serde(default)]
+    pub velocity: Option<f32>,
+}
+
+/// Commands are written on a line of their own. `{pen}` in `pen_change` is replaced by the
+/// number of the next pen. Feed rates are in mm/min.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GCodeConfig {
+    pub pen_up: String,
+    pub pen_down: String,
+    pub pen_change: String,
+    pub draw_feed_rate: f32,
+    pub travel_feed_rate: f32,
+}
@@ -0,0 +1,118 @@
+use amethyst::ecs::Join;
+use amethyst::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::fs;
+
+use super::raster::{draw_background, draw_faded_trace, draw_trace, parse_colour, Canvas};
+use crate::components::Trace;
+use crate::config::{ChamberConfig, FrameSequenceConfig, OutputConfig};
+use crate::resources::{BackgroundNoise, SVGBuilder, SimulationTime};
+
+/// Renders the chamber to a numbered sequence of PNGs while the simulation runs
+pub struct FrameRecorder {
+    config: FrameSequenceConfig,
+    /// The materials and noise, which stay the same from frame to frame
+    background: Canvas,
+    next_frame: usize,
+}
+
+impl FrameRecorder {
+    /// A recorder for the frames in the output config, if there are any.
+    /// Expects the background noise to have been generated already.
+    pub fn new(world: &World) -> Option<FrameRecorder> {
+        let config = world.read_resource::<OutputConfig>().frames.clone()?;
+        fs::create_dir_all(&config.directory).expect("Failed to create the frame directory");
+
+        let background = render_background(world, config.width, config.height);
+
+        Some(FrameRecorder {
+            config,
+            background,
+            next_frame: 0,
+        })
+    }
+
+    /// Writes a frame for every frame time the simulation has passed since the last call.
+    /// If it stepped past several at once, they all get the same picture.
+    pub fn record(&mut self, world: &World) {
+        let now = world.read_resource::<SimulationTime>().elapsed;
+        let frame_rate = self.config.frame_rate;
+        let frame_time = |frame: usize| frame as f32 / frame_rate;
+        if frame_time(self.next_frame) > now {
+            return;
+        }
+
+        let canvas = render_frame(world, &self.background, self.config.fade, now);
+        while frame_time(self.next_frame) <= now {
+            let path = format!("{}/frame_{:05}.png", self.config.directory, self.next_frame);
+            canvas
+                .save_png(&path)
+                .unwrap_or_else(|e| panic!("Failed to write {}: {}", path, e));
+            self.next_frame += 1;
+        }
+    }
+}
+
+/// A `width` by `height` canvas with the chamber's materials and noise on it
+pub fn render_background(world: &World, width: u32, height: u32) -> Canvas {
+    let chamber = world.read_resource::<ChamberConfig>();
+    let mut background = Canvas::fit(width, height, &chamber);
+    draw_background(
+        &mut background,
+        &chamber,
+        &world.read_resource::<BackgroundNoise>(),
+    );
+
+    background
+}
+
+/// The tracks as they look at time `now`, drawn over a copy of `background`
+pub fn render_frame(world: &World, background: &Canvas, fade: Option<f32>, now: f32) -> Canvas {
+    let output = world.read_resource::<OutputConfig>();
+    let svg_builder = world.read_resource::<SVGBuilder>();
+    let live_traces = world.read_storage::<Trace>();
+
+    let mut canvas = background.clone();
+    let mut colours = HashMap::new();
+
+    // Finished traces and the ones still being left. Ghost tracks of neutral particles are
+    // only drawn in the SVG.
+    let traces = svg_builder
+        .paths
+        .iter()
+        .chain(live_traces.join())
+        .filter(|trace| trace.charge != 0);
+
+    for trace in traces {
+        let name = output.colours.for_track(trace.charge, trace.generation);
+        let colour = *colours.entry(name).or_insert_with(|| parse_colour(name));
+        // Seeded per trace, so its bubbles stay put from one frame to the next
+        let seed =
+            output.seed ^ trace.particle as u64 ^ (u64::from(trace.times[0].to_bits()) << 32);
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        match fade {
+            Some(half_life) => draw_faded_trace(
+                &mut canvas,
+                trace,
+                &output.track_style,
+                colour,
+                now,
+                half_life,
+                &mut rng,
+            ),
+            None => draw_trace(
+                &mut canvas,
+                trace,
+                &output.track_style,
+                colour,
+                1.0,
+                &mut rng,
+            ),
+        }
+    }
+
+    canvas
+}
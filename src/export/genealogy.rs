@@ -0,0 +1,143 @@
+use amethyst::ecs::Join;
+use amethyst::prelude::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::components::{LifeTime, Particle};
+use crate::config::{GenealogyFormat, OutputConfig};
+use crate::resources::{ParticleLog, ParticleRecord};
+
+/// A particle and everything that decayed out of it
+#[derive(Serialize)]
+struct DecayNode {
+    id: usize,
+    charges: [usize; 3],
+    charge: isize,
+    mass: usize,
+    generation: usize,
+    decay_time: f32,
+    products: Vec<DecayNode>,
+}
+
+/// All particles of the run, grouped by the particle they were produced by
+struct DecayTree {
+    particles: Vec<ParticleRecord>,
+    /// Indices into `particles` of the decay products of each particle id
+    products: HashMap<usize, Vec<usize>>,
+    /// Indices of the particles without a known parent
+    roots: Vec<usize>,
+}
+
+impl DecayTree {
+    fn new(mut particles: Vec<ParticleRecord>) -> DecayTree {
+        // Delta rays are knocked out of the medium, they aren't part of any decay
+        particles.retain(|particle| !particle.delta_ray);
+        particles.sort_by_key(|particle| particle.id);
+
+        let ids: HashSet<usize> = particles.iter().map(|particle| particle.id).collect();
+        let mut products: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut roots = Vec::new();
+
+        for (i, particle) in particles.iter().enumerate() {
+            match particle.parent {
+                Some(parent) if ids.contains(&parent) => {
+                    products.entry(parent).or_default().push(i)
+                }
+                _ => roots.push(i),
+            }
+        }
+
+        DecayTree {
+            particles,
+            products,
+            roots,
+        }
+    }
+
+    fn node(&self, index: usize) -> DecayNode {
+        let particle = &self.particles[index];
+        let products = self
+            .products
+            .get(&particle.id)
+            .map_or(Vec::new(), |products| {
+                products.iter().map(|&i| self.node(i)).collect()
+            });
+
+        DecayNode {
+            id: particle.id,
+            charges: particle.charges,
+            charge: particle.total_charge(),
+            mass: particle.mass,
+            generation: particle.generation,
+            decay_time: particle.decay_time,
+            products,
+        }
+    }
+}
+
+fn write_json(tree: &DecayTree, path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let roots: Vec<DecayNode> = tree.roots.iter().map(|&i| tree.node(i)).collect();
+
+    serde_json::to_writer_pretty(&mut file, &roots)?;
+    file.flush()
+}
+
+fn write_dot(tree: &DecayTree, path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "digraph decays {{")?;
+    writeln!(file, "    node [shape=box];")?;
+
+    for particle in &tree.particles {
+        writeln!(
+            file,
+            "    p{} [label=\"#{}\\ncharge {}, mass {}\\ngeneration {}\"];",
+            particle.id,
+            particle.id,
+            particle.total_charge(),
+            particle.mass,
+            particle.generation
+        )?;
+    }
+    for particle in &tree.particles {
+        if let Some(products) = tree.products.get(&particle.id) {
+            for &i in products {
+                writeln!(file, "    p{} -> p{};", particle.id, tree.particles[i].id)?;
+            }
+        }
+    }
+
+    writeln!(file, "}}")?;
+    file.flush()
+}
+
+pub fn output_genealogy(world: &mut World) {
+    let format = match world.read_resource::<OutputConfig>().genealogy {
+        Some(format) => format,
+        None => return,
+    };
+
+    let mut particles: Vec<ParticleRecord> =
+        world.read_resource::<ParticleLog>().particles.to_vec();
+
+    // Particles that are still around haven't been logged yet
+    let (alive, lifetimes) = (
+        world.read_storage::<Particle>(),
+        world.read_storage::<LifeTime>(),
+    );
+    for (particle, lifetime) in (&alive, &lifetimes).join() {
+        particles.push(ParticleRecord::new(particle, lifetime));
+    }
+
+    let tree = DecayTree::new(particles);
+
+    match format {
+        GenealogyFormat::Json => {
+            write_json(&tree, "decays.json").expect("Failed to write decays.json")
+        }
+        GenealogyFormat::Dot => write_dot(&tree, "decays.dot").expect("Failed to write decays.dot"),
+    }
+}
@@ -0,0 +1,224 @@
+use amethyst::prelude::*;
+use gif::{DisposalMethod, Encoder, Frame, Repeat, SetParameter};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use super::frames::{render_background, render_frame};
+use super::raster::{parse_colour, Canvas, Colour, WHITE};
+use crate::config::{ChamberConfig, GifConfig, OutputConfig};
+use crate::resources::SimulationTime;
+
+/// The most colours a GIF palette can hold
+const PALETTE_SIZE: usize = 256;
+
+/// The chamber is mostly black, with a handful of colours blended into it at all sorts of
+/// opacities. So instead of a general purpose quantiser, the palette is black plus an even ramp
+/// of shades up to each of those colours.
+struct Palette {
+    colours: Vec<[u8; 3]>,
+    /// Palette index of every pixel colour seen so far
+    lookup: HashMap<[u8; 3], u8>,
+}
+
+impl Palette {
+    fn new(colours: &[Colour]) -> Palette {
+        let to_rgb8 = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+
+        let mut unique: Vec<[u8; 3]> = Vec::new();
+        for colour in colours {
+            let colour = [to_rgb8(colour[0]), to_rgb8(colour[1]), to_rgb8(colour[2])];
+            if colour != [0, 0, 0] && !unique.contains(&colour) {
+                unique.push(colour);
+            }
+        }
+        unique.truncate(PALETTE_SIZE - 1);
+
+        let shades = (PALETTE_SIZE - 1) / unique.len().max(1);
+        let mut palette = vec![[0, 0, 0]];
+        for colour in &unique {
+            for i in 1..=shades {
+                let level = i as f32 / shades as f32;
+                palette.push([
+                    (f32::from(colour[0]) * level).round() as u8,
+                    (f32::from(colour[1]) * level).round() as u8,
+                    (f32::from(colour[2]) * level).round() as u8,
+                ]);
+            }
+        }
+
+        Palette {
+            colours: palette,
+            lookup: HashMap::new(),
+        }
+    }
+
+    /// The index of the palette colour closest to `pixel`
+    fn index(&mut self, pixel: [u8; 3]) -> u8 {
+        let colours = &self.colours;
+
+        *self.lookup.entry(pixel).or_insert_with(|| {
+            let distance = |colour: &[u8; 3]| {
+                (0..3)
+                    .map(|c| (i32::from(colour[c]) - i32::from(pixel[c])).pow(2))
+                    .sum::<i32>()
+            };
+            (0..colours.len())
+                .min_by_key(|&i| distance(&colours[i]))
+                .unwrap_or(0) as u8
+        })
+    }
+
+    fn to_rgb8(&self) -> Vec<u8> {
+        self.colours
+            .iter()
+            .flat_map(|colour| colour.to_vec())
+            .collect()
+    }
+}
+
+/// Encodes the chamber into particles.gif while the simulation runs.
+/// Frames only hold the rectangle that changed since the previous one.
+pub struct GifRecorder {
+    config: GifConfig,
+    background: Canvas,
+    palette: Palette,
+    encoder: Encoder<BufWriter<File>>,
+    /// The last frame that was written, as palette indices
+    previous: Option<Vec<u8>>,
+    next_frame: usize,
+}
+
+impl GifRecorder {
+    /// A recorder for the GIF in the output config, if there is one.
+    /// Expects the background noise to have been generated already.
+    /// The GIF is finished when the recorder gets dropped.
+    pub fn new(world: &World) -> Option<GifRecorder> {
+        let output = world.read_resource::<OutputConfig>();
+        let config = output.gif.clone()?;
+
+        // Everything that can end up in the picture: noise, materials and tracks
+        let mut colours = vec![WHITE];
+        for material in &world.read_resource::<ChamberConfig>().materials {
+            if let Some(fill) = &material.fill {
+                colours.push(parse_colour(fill));
+            }
+        }
+        colours.push(parse_colour(&output.colours.positive));
+        colours.push(parse_colour(&output.colours.negative));
+        for colour in &output.colours.by_generation {
+            colours.push(parse_colour(colour));
+        }
+        let palette = Palette::new(&colours);
+
+        let file =
+            BufWriter::new(File::create("particles.gif").expect("Failed to create particles.gif"));
+        let mut encoder = Encoder::new(file, config.width, config.height, &palette.to_rgb8())
+            .expect("Failed to write particles.gif");
+        encoder
+            .set(Repeat::Infinite)
+            .expect("Failed to write particles.gif");
+
+        let background =
+            render_background(world, u32::from(config.width), u32::from(config.height));
+
+        Some(GifRecorder {
+            config,
+            background,
+            palette,
+            encoder,
+            previous: None,
+            next_frame: 0,
+        })
+    }
+
+    /// Adds a frame if the simulation has passed the next frame time since the last call.
+    /// If it stepped past several at once, the frame is shown for as long as all of them.
+    pub fn record(&mut self, world: &World) {
+        let now = world.read_resource::<SimulationTime>().elapsed;
+        let frame_rate = self.config.frame_rate;
+        let frame_time = |frame: usize| frame as f32 / frame_rate;
+        if frame_time(self.next_frame) > now {
+            return;
+        }
+
+        let first = self.next_frame;
+        while frame_time(self.next_frame) <= now {
+            self.next_frame += 1;
+        }
+        // GIF delays are in hundredths of a second. Rounding the time of every frame instead of
+        // every delay keeps the rounding errors from adding up.
+        // Working in u64 keeps long recordings from overflowing before the delay is taken.
+        let centiseconds =
+            |frame: usize| (frame as f64 * 100.0 / f64::from(frame_rate)).round() as u64;
+        let delay = (centiseconds(self.next_frame) - centiseconds(first))
+            .min(u64::from(std::u16::MAX)) as u16;
+
+        let canvas = render_frame(world, &self.background, self.config.fade, now);
+        let indices: Vec<u8> = canvas
+            .to_rgb8()
+            .chunks(3)
+            .map(|pixel| self.palette.index([pixel[0], pixel[1], pixel[2]]))
+            .collect();
+
+        self.write_frame(&indices, delay);
+        self.previous = Some(indices);
+    }
+
+    fn write_frame(&mut self, indices: &[u8], delay: u16) {
+        let width = usize::from(self.config.width);
+        let height = usize::from(self.config.height);
+        let (left, top, right, bottom) = match &self.previous {
+            Some(previous) => changed_area(previous, indices, width).unwrap_or((0, 0, 1, 1)),
+            None => (0, 0, width, height),
+        };
+
+        let mut buffer = Vec::with_capacity((right - left) * (bottom - top));
+        for y in top..bottom {
+            buffer.extend_from_slice(&indices[y * width + left..y * width + right]);
+        }
+
+        let frame = Frame {
+            delay,
+            dispose: DisposalMethod::Keep,
+            left: left as u16,
+            top: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+            buffer: Cow::Owned(buffer),
+            ..Frame::default()
+        };
+        self.encoder
+            .write_frame(&frame)
+            .expect("Failed to write particles.gif");
+    }
+}
+
+/// The smallest rectangle holding every pixel that differs between `previous` and `current`,
+/// as left, top, right and bottom, with the right and bottom exclusive.
+/// `None` if nothing changed.
+fn changed_area(
+    previous: &[u8],
+    current: &[u8],
+    width: usize,
+) -> Option<(usize, usize, usize, usize)> {
+    let mut area: Option<(usize, usize, usize, usize)> = None;
+
+    for (i, _) in previous
+        .iter()
+        .zip(current)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+    {
+        let (x, y) = (i % width, i / width);
+        area = Some(match area {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1))
+            }
+            None => (x, y, x + 1, y + 1),
+        });
+    }
+
+    area
+}
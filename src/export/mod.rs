@@ -0,0 +1,134 @@
+use rand::distributions::{Distribution, Exp1};
+use rand::Rng;
+
+use crate::components::Trace;
+use crate::config::{ChamberConfig, PrintConfig};
+
+mod frames;
+mod genealogy;
+mod gif;
+mod ordering;
+mod plotter;
+mod raster;
+mod tracks;
+mod vector;
+
+pub use self::frames::FrameRecorder;
+pub use self::genealogy::output_genealogy;
+pub use self::gif::GifRecorder;
+pub use self::plotter::output_plots;
+pub use self::raster::{output_png, Canvas};
+pub use self::tracks::output_tracks;
+pub use self::vector::output_svg;
+
+/// Where the chamber ends up on a page of `width` by `height`:
+/// chamber coordinates are multiplied by `scale` and moved by `offset`.
+struct Layout {
+    width: f32,
+    height: f32,
+    scale: f32,
+    offset: [f32; 2],
+}
+
+impl Layout {
+    /// Scales the chamber to fit inside `margin` and centers it on the page
+    fn fit(width: f32, height: f32, margin: f32, chamber: &ChamberConfig) -> Layout {
+        let scale =
+            ((width - 2.0 * margin) / chamber.width).min((height - 2.0 * margin) / chamber.height);
+
+        Layout {
+            width,
+            height,
+            scale,
+            offset: [
+                (width - chamber.width * scale) * 0.5,
+                (height - chamber.height * scale) * 0.5,
+            ],
+        }
+    }
+
+    /// The layout of a print in its physical units, including the bleed
+    fn print(print: &PrintConfig, chamber: &ChamberConfig) -> Layout {
+        let trimmed = Layout::fit(print.width, print.height, print.margin, chamber);
+
+        Layout {
+            width: trimmed.width + 2.0 * print.bleed,
+            height: trimmed.height + 2.0 * print.bleed,
+            scale: trimmed.scale,
+            offset: [
+                trimmed.offset[0] + print.bleed,
+                trimmed.offset[1] + print.bleed,
+            ],
+        }
+    }
+
+    /// The same layout with every length multiplied by `factor`, like going from inches to pixels
+    fn scaled(&self, factor: f32) -> Layout {
+        Layout {
+            width: self.width * factor,
+            height: self.height * factor,
+            scale: self.scale * factor,
+            offset: [self.offset[0] * factor, self.offset[1] * factor],
+        }
+    }
+
+    /// The page in chamber coordinates, as an SVG `viewBox`
+    fn view_box(&self) -> (f32, f32, f32, f32) {
+        (
+            -self.offset[0] / self.scale,
+            -self.offset[1] / self.scale,
+            self.width / self.scale,
+            self.height / self.scale,
+        )
+    }
+}
+
+/// Places bubbles along a trace as a Poisson process. The density of bubbles follows the
+/// ionisation of the particle, which is proportional to `(charge / speed)^2`.
+/// Returns the center, radius and simulation time of every bubble.
+fn place_bubbles<R: Rng>(
+    trace: &Trace,
+    spacing: f32,
+    radius: f32,
+    jitter: f32,
+    reference_speed: f32,
+    rng: &mut R,
+) -> Vec<([f32; 2], f32, f32)> {
+    let charge_squared = (trace.charge * trace.charge) as f32;
+    let mut bubbles = Vec::new();
+
+    // Distance to the next bubble, in units of the local mean spacing
+    let mut until_next = Exp1.sample(rng) as f32;
+
+    for (i, segment) in trace.points.windows(2).enumerate() {
+        let (start, end) = (segment[0], segment[1]);
+        let length = (end[0] - start[0]).hypot(end[1] - start[1]);
+        let speed = ((trace.speed(i) + trace.speed(i + 1)) * 0.5).max(std::f32::EPSILON);
+        let ionisation = (charge_squared * (reference_speed / speed).powi(2))
+            .max(0.05)
+            .min(20.0);
+
+        let depth = length * ionisation / spacing;
+        let mut travelled = 0.0;
+
+        while travelled + until_next <= depth {
+            travelled += until_next;
+            let t = travelled / depth;
+
+            bubbles.push((
+                [
+                    start[0] + (end[0] - start[0]) * t,
+                    start[1] + (end[1] - start[1]) * t,
+                ],
+                radius * (1.0 + jitter * rng.gen_range(-1.0, 1.0)),
+                trace.times[i] + (trace.times[i + 1] - trace.times[i]) * t,
+            ));
+
+            until_next = Exp1.sample(rng) as f32;
+        }
+
+        until_next -= depth - travelled;
+    }
+
+    bubbles
+}
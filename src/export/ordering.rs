@@ -0,0 +1,283 @@
+use crate::config::PathOrderConfig;
+
+/// Gives up on 2-opt after this many passes over the paths, even if it still finds improvements
+const MAX_TWO_OPT_PASSES: usize = 50;
+/// Gives up on 2-opt after trying this many moves, so pens with many paths don't stall it.
+/// Each pass tries about n^2 / 2 of them.
+const MAX_TWO_OPT_MOVES: usize = 20_000_000;
+
+/// How much pen-up travel an ordering pass saved
+#[derive(Default)]
+pub struct OrderingStats {
+    pub travel_before: f32,
+    pub travel_after: f32,
+    pub merged: usize,
+}
+
+/// Reorders `paths` to minimise the travel between them, starting from the origin: a nearest
+/// neighbour tour, improved with 2-opt. Paths ending close to the start of the next are merged.
+pub fn order_paths(paths: &mut Vec<Vec<[f32; 2]>>, config: &PathOrderConfig) -> OrderingStats {
+    let travel_before = travel(paths);
+
+    nearest_neighbour(paths, config.reverse);
+    two_opt(paths, config.reverse, MAX_TWO_OPT_MOVES);
+    let merged = merge_touching(paths, config.merge_distance);
+
+    OrderingStats {
+        travel_before,
+        travel_after: travel(paths),
+        merged,
+    }
+}
+
+fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+    (a[0] - b[0]).hypot(a[1] - b[1])
+}
+
+fn first(path: &[[f32; 2]]) -> [f32; 2] {
+    path[0]
+}
+
+fn last(path: &[[f32; 2]]) -> [f32; 2] {
+    path[path.len() - 1]
+}
+
+/// Total distance travelled between paths, starting from the origin
+fn travel(paths: &[Vec<[f32; 2]>]) -> f32 {
+    let mut position = [0.0, 0.0];
+    let mut total = 0.0;
+
+    for path in paths {
+        total += distance(position, first(path));
+        position = last(path);
+    }
+
+    total
+}
+
+fn nearest_neighbour(paths: &mut Vec<Vec<[f32; 2]>>, reverse: bool) {
+    let mut remaining = std::mem::replace(paths, Vec::new());
+    let mut position = [0.0, 0.0];
+
+    while !remaining.is_empty() {
+        let mut best = (0, false, std::f32::INFINITY);
+
+        for (i, path) in remaining.iter().enumerate() {
+            let to_start = distance(position, first(path));
+            if to_start < best.2 {
+                best = (i, false, to_start);
+            }
+
+            let to_end = distance(position, last(path));
+            if reverse && to_end < best.2 {
+                best = (i, true, to_end);
+            }
+        }
+
+        let mut path = remaining.swap_remove(best.0);
+        if best.1 {
+            path.reverse();
+        }
+        position = last(&path);
+        paths.push(path);
+    }
+}
+
+/// Improves the order of the paths until no move shortens the travel anymore.
+/// If paths can be reversed, a move reverses a run of paths, and each path in them.
+/// A run can be a single path, which then just gets drawn the other way around.
+/// The travel inside a reversed run stays the same, so only the two moves at its ends change.
+/// Otherwise, a move swaps two paths, keeping the direction of both.
+/// Gives up after trying `max_moves` moves.
+fn two_opt(paths: &mut Vec<Vec<[f32; 2]>>, reverse: bool, max_moves: usize) {
+    let n = paths.len();
+    let mut tried = 0;
+
+    for _ in 0..MAX_TWO_OPT_PASSES {
+        let mut improved = false;
+
+        for i in 0..n {
+            for j in i..n {
+                tried += 1;
+                if tried > max_moves {
+                    return;
+                }
+                if !reverse {
+                    if j > i && swap_if_shorter(paths, i, j) {
+                        improved = true;
+                    }
+                    continue;
+                }
+
+                let before = if i == 0 {
+                    [0.0, 0.0]
+                } else {
+                    last(&paths[i - 1])
+                };
+                let (start, end) = (first(&paths[i]), last(&paths[j]));
+
+                let mut old = distance(before, start);
+                let mut new = distance(before, end);
+                if j + 1 < n {
+                    let after = first(&paths[j + 1]);
+                    old += distance(end, after);
+                    new += distance(start, after);
+                }
+
+                if new + std::f32::EPSILON < old {
+                    paths[i..=j].reverse();
+                    for path in &mut paths[i..=j] {
+                        path.reverse();
+                    }
+                    improved = true;
+                }
+            }
+        }
+
+        if !improved {
+            break;
+        }
+    }
+}
+
+/// Swaps paths `i` and `j` if that shortens the travel, and says whether it did
+fn swap_if_shorter(paths: &mut [Vec<[f32; 2]>], i: usize, j: usize) -> bool {
+    // The moves towards and away from both paths, each counted once if they're neighbours
+    let mut moves = [0; 4];
+    let mut count = 0;
+    for &k in &[i, i + 1, j, j + 1] {
+        if k < paths.len() && !moves[..count].contains(&k) {
+            moves[count] = k;
+            count += 1;
+        }
+    }
+    let moves = &moves[..count];
+
+    let cost = |paths: &[Vec<[f32; 2]>]| -> f32 {
+        moves
+            .iter()
+            .map(|&k| {
+                let from = if k == 0 {
+                    [0.0, 0.0]
+                } else {
+                    last(&paths[k - 1])
+                };
+                distance(from, first(&paths[k]))
+            })
+            .sum()
+    };
+
+    let before = cost(paths);
+    paths.swap(i, j);
+    if cost(paths) + std::f32::EPSILON < before {
+        true
+    } else {
+        paths.swap(i, j);
+        false
+    }
+}
+
+/// Joins paths that end within `max_distance` of the start of the next one, drawing the gap
+/// between them. Returns how many joins were made.
+fn merge_touching(paths: &mut Vec<Vec<[f32; 2]>>, max_distance: f32) -> usize {
+    let mut merged: Vec<Vec<[f32; 2]>> = Vec::new();
+    let mut joins = 0;
+
+    for path in paths.drain(..) {
+        match merged.last_mut() {
+            Some(previous) if distance(last(previous), first(&path)) <= max_distance => {
+                previous.extend_from_slice(&path);
+                joins += 1;
+            }
+            _ => merged.push(path),
+        }
+    }
+
+    *paths = merged;
+    joins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverses_a_single_path_towards_the_origin() {
+        let mut paths = vec![vec![[10.0, 0.0], [1.0, 0.0]]];
+
+        two_opt(&mut paths, true, MAX_TWO_OPT_MOVES);
+
+        assert_eq!(paths, vec![vec![[1.0, 0.0], [10.0, 0.0]]]);
+    }
+
+    #[test]
+    fn reverses_runs_of_paths() {
+        let mut paths = vec![vec![[10.0, 0.0], [9.0, 0.0]], vec![[2.0, 0.0], [1.0, 0.0]]];
+
+        two_opt(&mut paths, true, MAX_TWO_OPT_MOVES);
+
+        assert_eq!(
+            paths,
+            vec![vec![[1.0, 0.0], [2.0, 0.0]], vec![[9.0, 0.0], [10.0, 0.0]]]
+        );
+        assert_eq!(travel(&paths), 8.0);
+    }
+
+    #[test]
+    fn swaps_paths_without_reversing_them() {
+        let mut paths = vec![vec![[10.0, 0.0], [11.0, 0.0]], vec![[1.0, 0.0], [2.0, 0.0]]];
+
+        two_opt(&mut paths, false, MAX_TWO_OPT_MOVES);
+
+        assert_eq!(
+            paths,
+            vec![vec![[1.0, 0.0], [2.0, 0.0]], vec![[10.0, 0.0], [11.0, 0.0]]]
+        );
+    }
+
+    #[test]
+    fn keeps_directions_without_reversal() {
+        let mut paths = vec![vec![[10.0, 0.0], [1.0, 0.0]]];
+
+        two_opt(&mut paths, false, MAX_TWO_OPT_MOVES);
+
+        assert_eq!(paths, vec![vec![[10.0, 0.0], [1.0, 0.0]]]);
+    }
+
+    #[test]
+    fn stops_after_the_move_cap() {
+        let mut paths = vec![vec![[10.0, 0.0], [1.0, 0.0]]];
+
+        two_opt(&mut paths, true, 0);
+
+        assert_eq!(paths, vec![vec![[10.0, 0.0], [1.0, 0.0]]]);
+    }
+
+    #[test]
+    fn nearest_neighbour_only_reverses_if_allowed() {
+        let far = vec![[5.0, 0.0], [6.0, 0.0]];
+        let backwards = vec![[3.0, 0.0], [1.0, 0.0]];
+
+        let mut paths = vec![far.clone(), backwards.clone()];
+        nearest_neighbour(&mut paths, true);
+        assert_eq!(paths, vec![vec![[1.0, 0.0], [3.0, 0.0]], far.clone()]);
+
+        let mut paths = vec![far.clone(), backwards.clone()];
+        nearest_neighbour(&mut paths, false);
+        assert_eq!(paths, vec![backwards, far]);
+    }
+
+    #[test]
+    fn merges_paths_that_touch() {
+        let mut paths = vec![
+            vec![[0.0, 0.0], [1.0, 0.0]],
+            vec![[1.05, 0.0], [2.0, 0.0]],
+            vec![[2.1, 0.0], [3.0, 0.0]],
+            vec![[5.0, 0.0], [6.0, 0.0]],
+        ];
+
+        assert_eq!(merge_touching(&mut paths, 0.2), 2);
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].len(), 6);
+    }
+}
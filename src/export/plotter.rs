@@ -0,0 +1,175 @@
+use amethyst::prelude::*;
+use log::info;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::ordering::{order_paths, OrderingStats};
+use super::Layout;
+use crate::config::{ChamberConfig, GCodeConfig, HpglConfig, OutputConfig, PlotterConfig};
+use crate::resources::SVGBuilder;
+
+/// HPGL plotter units per millimetre
+const HPGL_UNITS_PER_MM: f32 = 40.0;
+
+/// The polylines to draw with each pen, in millimetres on the plotter's bed
+type Plot = BTreeMap<usize, Vec<Vec<[f32; 2]>>>;
+
+/// Clips the tracks to the chamber and scales them onto the bed.
+/// The bed's origin is in its bottom-left corner, so the tracks are flipped vertically to end up
+/// the same way up as in the SVG.
+fn plot_tracks(
+    svg_builder: &SVGBuilder,
+    chamber: &ChamberConfig,
+    output: &OutputConfig,
+    plotter: &PlotterConfig,
+) -> Plot {
+    let outline = chamber.outline();
+    let layout = Layout::fit(plotter.bed[0], plotter.bed[1], plotter.margin, chamber);
+    let mut plot = Plot::new();
+
+    // Ghost tracks of neutral particles aren't plotted
+    for trace in svg_builder.paths.iter().filter(|trace| trace.charge != 0) {
+        let pen = plotter.pen_for(output.colours.for_track(trace.charge, trace.generation));
+
+        for piece in outline.clip_polyline(&trace.points) {
+            let on_bed = piece
+                .iter()
+                .map(|point| {
+                    [
+                        point[0] * layout.scale + layout.offset[0],
+                        layout.height - (point[1] * layout.scale + layout.offset[1]),
+                    ]
+                })
+                .collect();
+
+            plot.entry(pen).or_default().push(on_bed);
+        }
+    }
+
+    plot
+}
+
+fn write_hpgl(plot: &Plot, config: &HpglConfig, path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let units = |point: &[f32; 2]| {
+        (
+            (point[0] * HPGL_UNITS_PER_MM).round() as i32,
+            (point[1] * HPGL_UNITS_PER_MM).round() as i32,
+        )
+    };
+
+    writeln!(file, "IN;")?;
+    if let Some(velocity) = config.velocity {
+        writeln!(file, "VS{};", velocity)?;
+    }
+
+    for (pen, polylines) in plot {
+        writeln!(file, "SP{};", pen)?;
+
+        for polyline in polylines {
+            let (x, y) = units(&polyline[0]);
+            writeln!(file, "PU{},{};", x, y)?;
+
+            let coordinates: Vec<String> = polyline[1..]
+                .iter()
+                .map(|point| {
+                    let (x, y) = units(point);
+                    format!("{},{}", x, y)
+                })
+                .collect();
+            writeln!(file, "PD{};", coordinates.join(","))?;
+        }
+    }
+
+    writeln!(file, "PU;")?;
+    writeln!(file, "SP0;")?;
+    file.flush()
+}
+
+/// The plotter is assumed to start out holding pen 1, so no pen change is written for it
+/// before the first track.
+fn write_gcode(plot: &Plot, config: &GCodeConfig, path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "G21")?;
+    writeln!(file, "G90")?;
+    writeln!(file, "{}", config.pen_up)?;
+
+    let mut current_pen = 1;
+    for (pen, polylines) in plot {
+        if *pen != current_pen {
+            writeln!(
+                file,
+                "{}",
+                config.pen_change.replace("{pen}", &pen.to_string())
+            )?;
+            current_pen = *pen;
+        }
+
+        for polyline in polylines {
+            writeln!(
+                file,
+                "G0 X{:.3} Y{:.3} F{}",
+                polyline[0][0], polyline[0][1], config.travel_feed_rate
+            )?;
+            writeln!(file, "{}", config.pen_down)?;
+
+            for (i, point) in polyline[1..].iter().enumerate() {
+                if i == 0 {
+                    writeln!(
+                        file,
+                        "G1 X{:.3} Y{:.3} F{}",
+                        point[0], point[1], config.draw_feed_rate
+                    )?;
+                } else {
+                    writeln!(file, "G1 X{:.3} Y{:.3}", point[0], point[1])?;
+                }
+            }
+
+            writeln!(file, "{}", config.pen_up)?;
+        }
+    }
+
+    writeln!(file, "G0 X0 Y0 F{}", config.travel_feed_rate)?;
+    file.flush()
+}
+
+pub fn output_plots(world: &mut World) {
+    let output = world.read_resource::<OutputConfig>();
+    let plotter = match &output.plotter {
+        Some(plotter) => plotter,
+        None => return,
+    };
+
+    let chamber = world.read_resource::<ChamberConfig>();
+    let svg_builder = world.read_resource::<SVGBuilder>();
+    let mut plot = plot_tracks(&svg_builder, &chamber, &output, plotter);
+
+    if let Some(optimise) = &plotter.optimise {
+        let mut total = OrderingStats::default();
+        for paths in plot.values_mut() {
+            let stats = order_paths(paths, optimise);
+            total.travel_before += stats.travel_before;
+            total.travel_after += stats.travel_after;
+            total.merged += stats.merged;
+        }
+
+        let saved = total.travel_before - total.travel_after;
+        info!(
+            "Pen-up travel went from {:.0}mm to {:.0}mm, saving {:.0}mm ({:.1}%). Merged {} tracks.",
+            total.travel_before,
+            total.travel_after,
+            saved,
+            100.0 * saved / total.travel_before.max(std::f32::EPSILON),
+            total.merged
+        );
+    }
+
+    if let Some(hpgl) = &plotter.hpgl {
+        write_hpgl(&plot, hpgl, "particles.hpgl").expect("Failed to write particles.hpgl");
+    }
+    if let Some(gcode) = &plotter.gcode {
+        write_gcode(&plot, gcode, "particles.gcode").expect("Failed to write particles.gcode");
+    }
+}
@@ -0,0 +1,485 @@
+use amethyst::prelude::*;
+use log::warn;
+use png::HasParameters;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use super::{place_bubbles, Layout};
+use crate::components::Trace;
+use crate::config::{ChamberConfig, OutputConfig, PrintConfig, TrackStyle};
+use crate::geometry::{nearest_on_segment, Shape};
+use crate::resources::{BackgroundNoise, SVGBuilder};
+
+pub type Colour = [f32; 3];
+
+pub const WHITE: Colour = [1.0, 1.0, 1.0];
+
+/// Canvases are only rendered up to this many pixels, a bit over 400 MB
+const MAX_PIXELS: u64 = 20_000_000;
+/// Memory a canvas takes per pixel: the colour, the coverage and the clip mask
+const BYTES_PER_PIXEL: u64 = 20;
+
+/// Opacities of fading tracks are rounded to this many levels, so runs of segments that fade
+/// alike can be stroked at once
+const FADE_LEVELS: f32 = 32.0;
+
+/// A software rasteriser for the handful of primitives the chamber is drawn with.
+/// Everything is anti-aliased and drawn in chamber coordinates, like the SVG.
+#[derive(Clone)]
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    /// Pixels per chamber unit
+    scale: f32,
+    /// Where the chamber origin lands on the canvas, in pixels
+    offset: [f32; 2],
+    pixels: Vec<Colour>,
+    clip: Option<Vec<f32>>,
+    /// Coverage of the shape being drawn, so overlapping parts of it aren't blended twice
+    coverage: Vec<f32>,
+    touched: Vec<usize>,
+}
+
+impl Canvas {
+    /// A black canvas of `width` by `height` pixels, with the chamber placed according to `layout`
+    fn new(width: u32, height: u32, layout: &Layout) -> Canvas {
+        let size = width as usize * height as usize;
+
+        Canvas {
+            width: width as usize,
+            height: height as usize,
+            scale: layout.scale,
+            offset: layout.offset,
+            pixels: vec![[0.0, 0.0, 0.0]; size],
+            clip: None,
+            coverage: vec![0.0; size],
+            touched: Vec::new(),
+        }
+    }
+
+    /// A black canvas with the chamber scaled to fit and centered, like an SVG `viewBox`
+    pub fn fit(width: u32, height: u32, chamber: &ChamberConfig) -> Canvas {
+        let layout = Layout::fit(width as f32, height as f32, 0.0, chamber);
+        Canvas::new(width, height, &layout)
+    }
+
+    /// A canvas for `print` at its DPI, including its margins and bleed
+    pub fn print(print: &PrintConfig, chamber: &ChamberConfig) -> Canvas {
+        let layout = print_layout(print, chamber);
+        Canvas::new(
+            layout.width.round() as u32,
+            layout.height.round() as u32,
+            &layout,
+        )
+    }
+
+    /// Only draw inside of `shape` from now on
+    pub fn clip_to(&mut self, shape: &Shape) {
+        let mut clip = vec![0.0; self.width * self.height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                clip[y * self.width + x] = self.shape_coverage(shape, x, y);
+            }
+        }
+
+        self.clip = Some(clip);
+    }
+
+    pub fn fill_shape(&mut self, shape: &Shape, colour: Colour, opacity: f32) {
+        let (min, max) = shape.bounds();
+        let (min_x, min_y, max_x, max_y) = self.pixel_box(self.to_pixel(min), self.to_pixel(max));
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let coverage = self.shape_coverage(shape, x, y);
+                self.mark(y * self.width + x, coverage);
+            }
+        }
+
+        self.flush(colour, opacity);
+    }
+
+    pub fn fill_circle(&mut self, center: [f32; 2], radius: f32, colour: Colour, opacity: f32) {
+        let c = self.to_pixel(center);
+        let r = radius * self.scale;
+        let (min_x, min_y, max_x, max_y) =
+            self.pixel_box([c[0] - r, c[1] - r], [c[0] + r, c[1] + r]);
+        // Circles smaller than a pixel cover a fraction of it
+        let max_coverage = (std::f32::consts::PI * r * r).min(1.0);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let distance = (x as f32 + 0.5 - c[0]).hypot(y as f32 + 0.5 - c[1]);
+                let coverage = (r + 0.5 - distance).max(0.0).min(max_coverage);
+                self.mark(y * self.width + x, coverage);
+            }
+        }
+
+        self.flush(colour, opacity);
+    }
+
+    pub fn stroke_polyline(
+        &mut self,
+        points: &[[f32; 2]],
+        width: f32,
+        colour: Colour,
+        opacity: f32,
+    ) {
+        let half = width * self.scale * 0.5;
+        // Lines thinner than a pixel cover a fraction of it
+        let max_coverage = (half * 2.0).min(1.0);
+
+        for segment in points.windows(2) {
+            let (a, b) = (self.to_pixel(segment[0]), self.to_pixel(segment[1]));
+            let (min_x, min_y, max_x, max_y) = self.pixel_box(
+                [a[0].min(b[0]) - half, a[1].min(b[1]) - half],
+                [a[0].max(b[0]) + half, a[1].max(b[1]) + half],
+            );
+
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    let p = [x as f32 + 0.5, y as f32 + 0.5];
+                    let nearest = nearest_on_segment(p, a, b);
+                    let distance = (p[0] - nearest[0]).hypot(p[1] - nearest[1]);
+                    let coverage = (half + 0.5 - distance).max(0.0).min(max_coverage);
+                    self.mark(y * self.width + x, coverage);
+                }
+            }
+        }
+
+        self.flush(colour, opacity);
+    }
+
+    /// Pixels as 8-bit RGB, row by row
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|pixel| {
+                pixel
+                    .iter()
+                    .map(|c| (c.max(0.0).min(1.0) * 255.0).round() as u8)
+            })
+            .collect()
+    }
+
+    /// Fades everything drawn so far towards black, keeping `opacity` of it
+    pub fn fade(&mut self, opacity: f32) {
+        for pixel in &mut self.pixels {
+            for c in pixel.iter_mut() {
+                *c *= opacity;
+            }
+        }
+    }
+
+    /// Pixels as 8-bit RGBA, row by row, with the black background left transparent
+    pub fn to_rgba8(&self) -> Vec<[u8; 4]> {
+        let to_u8 = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+
+        self.pixels
+            .iter()
+            .map(|pixel| {
+                let alpha = pixel[0].max(pixel[1]).max(pixel[2]).max(0.0).min(1.0);
+                if alpha == 0.0 {
+                    return [0, 0, 0, 0];
+                }
+                [
+                    to_u8(pixel[0] / alpha),
+                    to_u8(pixel[1] / alpha),
+                    to_u8(pixel[2] / alpha),
+                    to_u8(alpha),
+                ]
+            })
+            .collect()
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), png::EncodingError> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_rgb8())
+    }
+
+    fn to_pixel(&self, point: [f32; 2]) -> [f32; 2] {
+        [
+            point[0] * self.scale + self.offset[0],
+            point[1] * self.scale + self.offset[1],
+        ]
+    }
+
+    fn to_chamber(&self, pixel: [f32; 2]) -> [f32; 2] {
+        [
+            (pixel[0] - self.offset[0]) / self.scale,
+            (pixel[1] - self.offset[1]) / self.scale,
+        ]
+    }
+
+    /// The range of pixels overlapping a box in pixel coordinates, with a pixel to spare
+    /// for anti-aliasing and clamped to the canvas
+    fn pixel_box(&self, min: [f32; 2], max: [f32; 2]) -> (usize, usize, usize, usize) {
+        let clamp = |v: f32, limit: usize| v.max(0.0).min(limit as f32) as usize;
+
+        (
+            clamp((min[0] - 1.0).floor(), self.width),
+            clamp((min[1] - 1.0).floor(), self.height),
+            clamp((max[0] + 1.0).ceil(), self.width),
+            clamp((max[1] + 1.0).ceil(), self.height),
+        )
+    }
+
+    /// The fraction of pixel `(x, y)` covered by `shape`. Only pixels on the outline are
+    /// supersampled.
+    fn shape_coverage(&self, shape: &Shape, x: usize, y: usize) -> f32 {
+        let (x, y) = (x as f32, y as f32);
+        let corners = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]]
+            .iter()
+            .filter(|[dx, dy]| shape.contains(self.to_chamber([x + dx, y + dy])))
+            .count();
+
+        match corners {
+            0 => 0.0,
+            4 => 1.0,
+            _ => {
+                let mut inside = 0;
+                for i in 0..4 {
+                    for j in 0..4 {
+                        let sample = [x + (i as f32 + 0.5) / 4.0, y + (j as f32 + 0.5) / 4.0];
+                        if shape.contains(self.to_chamber(sample)) {
+                            inside += 1;
+                        }
+                    }
+                }
+                inside as f32 / 16.0
+            }
+        }
+    }
+
+    fn mark(&mut self, index: usize, coverage: f32) {
+        if coverage <= self.coverage[index] {
+            return;
+        }
+        if self.coverage[index] == 0.0 {
+            self.touched.push(index);
+        }
+        self.coverage[index] = coverage;
+    }
+
+    /// Blends the marked coverage into the pixels
+    fn flush(&mut self, colour: Colour, opacity: f32) {
+        for &index in &self.touched {
+            let clip = self.clip.as_ref().map_or(1.0, |clip| clip[index]);
+            let alpha = self.coverage[index] * opacity * clip;
+            let pixel = &mut self.pixels[index];
+
+            for c in 0..3 {
+                pixel[c] += (colour[c] - pixel[c]) * alpha;
+            }
+            self.coverage[index] = 0.0;
+        }
+
+        self.touched.clear();
+    }
+}
+
+/// The layout of a print in pixels at its DPI
+fn print_layout(print: &PrintConfig, chamber: &ChamberConfig) -> Layout {
+    Layout::print(print, chamber).scaled(print.dpi / print.unit.per_inch())
+}
+
+/// Parses the CSS colours used in the config: `#rgb`, `#rrggbb` or a basic colour name
+pub fn parse_colour(colour: &str) -> Colour {
+    let hex = |digits: &str| {
+        u8::from_str_radix(digits, 16)
+            .ok()
+            .map(|v| v as f32 / 255.0)
+    };
+    let parsed = match colour.trim_start_matches('#') {
+        digits if colour.starts_with('#') && digits.len() == 6 => {
+            match (hex(&digits[0..2]), hex(&digits[2..4]), hex(&digits[4..6])) {
+                (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+                _ => None,
+            }
+        }
+        digits if colour.starts_with('#') && digits.len() == 3 => {
+            let double = |i: usize| hex(&digits[i..=i].repeat(2));
+            match (double(0), double(1), double(2)) {
+                (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+                _ => None,
+            }
+        }
+        "black" => Some([0.0, 0.0, 0.0]),
+        "white" => Some(WHITE),
+        "gray" | "grey" => Some([0.5, 0.5, 0.5]),
+        "red" => Some([1.0, 0.0, 0.0]),
+        "green" => Some([0.0, 0.5, 0.0]),
+        "blue" => Some([0.0, 0.0, 1.0]),
+        "yellow" => Some([1.0, 1.0, 0.0]),
+        "cyan" => Some([0.0, 1.0, 1.0]),
+        "magenta" => Some([1.0, 0.0, 1.0]),
+        "orange" => Some([1.0, 0.65, 0.0]),
+        _ => None,
+    };
+
+    parsed.unwrap_or_else(|| {
+        warn!("Unsupported colour {}, drawing it in white", colour);
+        WHITE
+    })
+}
+
+/// Draws the chamber's materials and background noise, clipped to its outline
+pub fn draw_background(canvas: &mut Canvas, chamber: &ChamberConfig, noise: &BackgroundNoise) {
+    canvas.clip_to(&chamber.outline());
+
+    for material in &chamber.materials {
+        if let Some(fill) = &material.fill {
+            canvas.fill_shape(&material.shape, parse_colour(fill), 1.0);
+        }
+    }
+    for (center, radius, opacity) in &noise.haze {
+        canvas.fill_circle(*center, *radius, WHITE, *opacity);
+    }
+    for (center, radius) in &noise.bubbles {
+        canvas.fill_circle(*center, *radius, WHITE, 0.8);
+    }
+    for track in &noise.stray_tracks {
+        canvas.stroke_polyline(track, 1.5, WHITE, 0.5);
+    }
+}
+
+pub fn draw_trace(
+    canvas: &mut Canvas,
+    trace: &Trace,
+    style: &TrackStyle,
+    colour: Colour,
+    opacity: f32,
+    rng: &mut StdRng,
+) {
+    match *style {
+        TrackStyle::Line { width } => canvas.stroke_polyline(&trace.points, width, colour, opacity),
+        TrackStyle::Bubbles {
+            spacing,
+            radius,
+            jitter,
+            reference_speed,
+        } => {
+            for (center, radius, _) in
+                place_bubbles(trace, spacing, radius, jitter, reference_speed, rng)
+            {
+                canvas.fill_circle(center, radius, colour, opacity);
+            }
+        }
+    }
+}
+
+/// Draws a trace as it looks at time `now`: every part of it loses half of its opacity
+/// `half_life` seconds after it was left
+pub fn draw_faded_trace(
+    canvas: &mut Canvas,
+    trace: &Trace,
+    style: &TrackStyle,
+    colour: Colour,
+    now: f32,
+    half_life: f32,
+    rng: &mut StdRng,
+) {
+    let opacity = |time: f32| {
+        let level = 0.5f32.powf((now - time).max(0.0) / half_life);
+        (level * FADE_LEVELS).round() / FADE_LEVELS
+    };
+
+    match *style {
+        TrackStyle::Line { width } => {
+            let mut run: Vec<[f32; 2]> = Vec::new();
+            let mut run_opacity = 0.0;
+
+            for (i, segment) in trace.points.windows(2).enumerate() {
+                let segment_opacity = opacity(trace.times[i + 1]);
+                if segment_opacity != run_opacity && !run.is_empty() {
+                    canvas.stroke_polyline(&run, width, colour, run_opacity);
+                    run.clear();
+                }
+                if run.is_empty() {
+                    run.push(segment[0]);
+                }
+                run.push(segment[1]);
+                run_opacity = segment_opacity;
+            }
+
+            if !run.is_empty() {
+                canvas.stroke_polyline(&run, width, colour, run_opacity);
+            }
+        }
+        TrackStyle::Bubbles {
+            spacing,
+            radius,
+            jitter,
+            reference_speed,
+        } => {
+            for (center, radius, time) in
+                place_bubbles(trace, spacing, radius, jitter, reference_speed, rng)
+            {
+                let bubble_opacity = opacity(time);
+                if bubble_opacity > 0.0 {
+                    canvas.fill_circle(center, radius, colour, bubble_opacity);
+                }
+            }
+        }
+    }
+}
+
+pub fn output_png(world: &mut World) {
+    let output = world.read_resource::<OutputConfig>();
+    let chamber = world.read_resource::<ChamberConfig>();
+    let noise = world.read_resource::<BackgroundNoise>();
+    let svg_builder = world.read_resource::<SVGBuilder>();
+
+    let (width, height) = match (&output.print, &output.png) {
+        (Some(print), _) => {
+            let layout = print_layout(print, &chamber);
+            (layout.width.round() as u64, layout.height.round() as u64)
+        }
+        (None, Some(png_config)) => (u64::from(png_config.width), u64::from(png_config.height)),
+        (None, None) => return,
+    };
+    if width * height > MAX_PIXELS {
+        warn!(
+            "Not writing particles.png: {}x{} pixels would take about {} MB to render. \
+             Lower the print's DPI or the PNG size.",
+            width,
+            height,
+            width * height * BYTES_PER_PIXEL / 1_000_000
+        );
+        return;
+    }
+
+    let mut canvas = match &output.print {
+        Some(print) => Canvas::print(print, &chamber),
+        None => Canvas::fit(width as u32, height as u32, &chamber),
+    };
+    draw_background(&mut canvas, &chamber, &noise);
+
+    // Same seed as the SVG, so bubbles end up in the same places
+    let mut rng = StdRng::seed_from_u64(output.seed);
+    let mut colours = HashMap::new();
+    // Ghost tracks of neutral particles are only drawn in the SVG
+    for trace in svg_builder.paths.iter().filter(|trace| trace.charge != 0) {
+        let name = output.colours.for_track(trace.charge, trace.generation);
+        let colour = *colours.entry(name).or_insert_with(|| parse_colour(name));
+        draw_trace(
+            &mut canvas,
+            trace,
+            &output.track_style,
+            colour,
+            1.0,
+            &mut rng,
+        );
+    }
+
+    canvas
+        .save_png("particles.png")
+        .expect("Failed to write particles.png");
+}
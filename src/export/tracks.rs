@@ -0,0 +1,139 @@
+use amethyst::ecs::Join;
+use amethyst::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::components::{LifeTime, Particle, Trace};
+use crate::config::{OutputConfig, TrackDataFormat};
+use crate::resources::{ParticleLog, ParticleRecord, SVGBuilder};
+
+/// A trace together with what's known about the particle that left it
+#[derive(Serialize)]
+struct TrackData<'a> {
+    trace: usize,
+    particle: usize,
+    parent: Option<usize>,
+    charges: [usize; 3],
+    charge: isize,
+    mass: usize,
+    decay_time: f32,
+    points: &'a [[f32; 2]],
+    times: &'a [f32],
+    velocities: &'a [[f32; 2]],
+}
+
+impl<'a> TrackData<'a> {
+    fn new(index: usize, trace: &'a Trace, particle: &ParticleRecord) -> TrackData<'a> {
+        TrackData {
+            trace: index,
+            particle: particle.id,
+            parent: particle.parent,
+            charges: particle.charges,
+            charge: trace.charge,
+            mass: particle.mass,
+            decay_time: particle.decay_time,
+            points: &trace.points,
+            times: &trace.times,
+            velocities: &trace.velocities,
+        }
+    }
+}
+
+fn write_json_lines(tracks: &[TrackData], path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    for track in tracks {
+        serde_json::to_writer(&mut file, track)?;
+        writeln!(file)?;
+    }
+
+    file.flush()
+}
+
+fn write_csv(tracks: &[TrackData], path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(
+        file,
+        "trace,particle,parent,positive,neutral,negative,charge,mass,decay_time,time,x,y,vx,vy"
+    )?;
+
+    for track in tracks {
+        let parent = track
+            .parent
+            .map_or(String::new(), |parent| parent.to_string());
+
+        for i in 0..track.points.len() {
+            writeln!(
+                file,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                track.trace,
+                track.particle,
+                parent,
+                track.charges[0],
+                track.charges[1],
+                track.charges[2],
+                track.charge,
+                track.mass,
+                track.decay_time,
+                track.times[i],
+                track.points[i][0],
+                track.points[i][1],
+                track.velocities[i][0],
+                track.velocities[i][1],
+            )?;
+        }
+    }
+
+    file.flush()
+}
+
+pub fn output_tracks(world: &mut World) {
+    let output = world.read_resource::<OutputConfig>();
+    let format = match output.track_data {
+        Some(format) => format,
+        None => return,
+    };
+
+    let svg_builder = world.read_resource::<SVGBuilder>();
+    let mut particles: HashMap<usize, ParticleRecord> = world
+        .read_resource::<ParticleLog>()
+        .particles
+        .iter()
+        .map(|particle| (particle.id, particle.clone()))
+        .collect();
+
+    // Particles that are still around haven't been logged yet, and their last piece of track
+    // is still on the entity
+    let (alive, lifetimes, live_traces) = (
+        world.read_storage::<Particle>(),
+        world.read_storage::<LifeTime>(),
+        world.read_storage::<Trace>(),
+    );
+    for (particle, lifetime) in (&alive, &lifetimes).join() {
+        particles.insert(particle.id, ParticleRecord::new(particle, lifetime));
+    }
+
+    let tracks: Vec<TrackData> = svg_builder
+        .paths
+        .iter()
+        .chain((&live_traces).join())
+        .enumerate()
+        .filter_map(|(i, trace)| {
+            particles
+                .get(&trace.particle)
+                .map(|particle| TrackData::new(i, trace, particle))
+        })
+        .collect();
+
+    match format {
+        TrackDataFormat::JsonLines => {
+            write_json_lines(&tracks, "particles.jsonl").expect("Failed to write particles.jsonl")
+        }
+        TrackDataFormat::Csv => {
+            write_csv(&tracks, "particles.csv").expect("Failed to write particles.csv")
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use amethyst::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use svg::node::element::path::Data;
+use svg::node::element::{Animate, Circle, ClipPath, Definitions, Group, Path, Rectangle};
+use svg::Document;
+
+use super::{place_bubbles, Layout};
+use crate::config::{
+    AnimationConfig, ChamberConfig, MarkerShape, OutputConfig, TrackStyle, VertexMarkerConfig,
+};
+use crate::geometry::Shape;
+use crate::resources::{BackgroundNoise, DecayVertices, SVGBuilder};
+
+/// At most this many keyframes are used to animate a single track
+const MAX_KEYFRAMES: usize = 64;
+
+/// How long it takes bubbles and markers to fade in, in seconds
+const FADE_IN: f32 = 0.1;
+
+pub fn output_svg(world: &mut World) {
+    let document = build_document(world, None);
+    svg::save("particles.svg", &document).unwrap();
+
+    let animation = world.read_resource::<OutputConfig>().animation.clone();
+    if let Some(animation) = animation {
+        let document = build_document(world, Some(&animation));
+        svg::save("particles_animated.svg", &document).unwrap();
+    }
+}
+
+/// The SVG of the whole chamber. With an `animation`, everything shows up at the moment it was
+/// simulated and tracks draw themselves as their particle moves.
+fn build_document(world: &World, animation: Option<&AnimationConfig>) -> Document {
+    let svg_builder = world.read_resource::<SVGBuilder>();
+
+    let chamber = world.read_resource::<ChamberConfig>();
+    let output = world.read_resource::<OutputConfig>();
+    let outline = chamber.outline();
+    let (document, view_box) = match &output.print {
+        Some(print) => {
+            let layout = Layout::print(print, &chamber);
+            let unit = print.unit.svg_suffix();
+            let document = Document::new()
+                .set("width", format!("{}{}", layout.width, unit))
+                .set("height", format!("{}{}", layout.height, unit));
+
+            (document, layout.view_box())
+        }
+        None => (Document::new(), (0.0, 0.0, chamber.width, chamber.height)),
+    };
+    let mut document = document.set("viewBox", view_box);
+
+    // Add a rect in black as background, covering the whole page including margins and bleed
+    document = document.add(
+        Rectangle::new()
+            .set("x", view_box.0)
+            .set("y", view_box.1)
+            .set("width", view_box.2)
+            .set("height", view_box.3)
+            .set("fill", "black"),
+    );
+
+    // Tracks are clipped to the chamber outline
+    document = document.add(
+        Definitions::new().add(
+            ClipPath::new()
+                .set("id", "chamber")
+                .add(Path::new().set("d", outline.svg_data())),
+        ),
+    );
+
+    let mut materials = Group::new().set("clip-path", "url(#chamber)");
+    for material in &chamber.materials {
+        if let Some(fill) = &material.fill {
+            materials = materials.add(
+                Path::new()
+                    .set("fill", fill.as_str())
+                    .set("d", material.shape.svg_data()),
+            );
+        }
+    }
+    document = document.add(materials);
+
+    let background_noise = world.read_resource::<BackgroundNoise>();
+    let mut noise = Group::new().set("clip-path", "url(#chamber)");
+    for (center, radius, opacity) in &background_noise.haze {
+        noise = noise.add(
+            Circle::new()
+                .set("cx", center[0])
+                .set("cy", center[1])
+                .set("r", *radius)
+                .set("fill", "white")
+                .set("fill-opacity", *opacity),
+        );
+    }
+    for (center, radius) in &background_noise.bubbles {
+        noise = noise.add(
+            Circle::new()
+                .set("cx", center[0])
+                .set("cy", center[1])
+                .set("r", *radius)
+                .set("fill", "white")
+                .set("fill-opacity", 0.8),
+        );
+    }
+    for track in &background_noise.stray_tracks {
+        noise = noise.add(
+            Path::new()
+                .set("fill", "none")
+                .set("stroke", "white")
+                .set("stroke-width", 1.5)
+                .set("stroke-opacity", 0.5)
+                .set("d", polyline_data(track)),
+        );
+    }
+    document = document.add(noise);
+
+    if let Some(ghosts) = &output.ghost_tracks {
+        let mut group = Group::new().set("clip-path", "url(#chamber)");
+
+        for trace in &svg_builder.paths {
+            if trace.charge != 0 || trace.points.len() < 2 {
+                continue;
+            }
+
+            let path = Path::new()
+                .set("class", "ghost")
+                .set("fill", "none")
+                .set("stroke", ghosts.colour.as_str())
+                .set("stroke-width", ghosts.width)
+                .set("stroke-opacity", ghosts.opacity)
+                .set("d", polyline_data(&trace.points));
+
+            // Drawing a line in uses its dashes, so dashed ghosts fade in as a whole instead
+            group = match (animation, ghosts.dash) {
+                (Some(animation), Some(dash)) => group.add(
+                    path.set("stroke-dasharray", dash)
+                        .set("opacity", 0)
+                        .add(fade_in(trace.times[0], animation)),
+                ),
+                (Some(animation), None) => {
+                    group.add(draw_in(path, &trace.points, &trace.times, animation))
+                }
+                (None, Some(dash)) => group.add(path.set("stroke-dasharray", dash)),
+                (None, None) => group.add(path),
+            };
+        }
+        document = document.add(group);
+    }
+
+    let mut rng = StdRng::seed_from_u64(output.seed);
+    let mut tracks = Group::new().set("clip-path", "url(#chamber)");
+
+    for trace in svg_builder.paths.iter().filter(|trace| trace.charge != 0) {
+        let colour = output.colours.for_track(trace.charge, trace.generation);
+        // Lets stylesheets pick out the tracks of a generation
+        let class = format!("generation-{}", trace.generation);
+
+        match (output.track_style.clone(), animation) {
+            (TrackStyle::Line { width }, Some(animation)) => {
+                // The curves below don't follow the points closely enough to animate along them
+                let path = Path::new()
+                    .set("fill", "none")
+                    .set("class", class.as_str())
+                    .set("stroke", colour)
+                    .set("stroke-width", width)
+                    .set("d", polyline_data(&trace.points));
+
+                tracks = tracks.add(draw_in(path, &trace.points, &trace.times, animation));
+            }
+            (TrackStyle::Line { width }, None) => {
+                let path = trace.points.as_slice();
+
+                // Starting point
+                let mut data = Data::new().move_to((path[0][0], path[0][1]));
+
+                // Make the curve
+                let all_points: Vec<f32> = path[1..].iter().flatten().cloned().collect();
+                data = data.cubic_curve_to(all_points);
+
+                let path = Path::new()
+                    .set("fill", "none")
+                    .set("class", class.as_str())
+                    .set("stroke", colour)
+                    .set("stroke-width", width)
+                    .set("d", data.to_owned());
+
+                tracks = tracks.add(path);
+            }
+            (
+                TrackStyle::Bubbles {
+                    spacing,
+                    radius,
+                    jitter,
+                    reference_speed,
+                },
+                _,
+            ) => {
+                let bubbles =
+                    place_bubbles(trace, spacing, radius, jitter, reference_speed, &mut rng);
+
+                for (center, radius, time) in bubbles {
+                    let bubble = Circle::new()
+                        .set("cx", center[0])
+                        .set("cy", center[1])
+                        .set("r", radius)
+                        .set("class", class.as_str())
+                        .set("fill", colour);
+
+                    tracks = match animation {
+                        Some(animation) => {
+                            tracks.add(bubble.set("opacity", 0).add(fade_in(time, animation)))
+                        }
+                        None => tracks.add(bubble),
+                    };
+                }
+            }
+        }
+    }
+
+    document = document.add(tracks);
+
+    if let Some(markers) = &output.vertex_markers {
+        let decay_vertices = world.read_resource::<DecayVertices>();
+        let mut group = Group::new().set("clip-path", "url(#chamber)");
+
+        for vertex in &decay_vertices.vertices {
+            let marker = vertex_marker(vertex.position, markers);
+
+            group = match animation {
+                Some(animation) => group.add(
+                    marker
+                        .set("opacity", 0)
+                        .add(fade_in(vertex.time, animation)),
+                ),
+                None => group.add(marker),
+            };
+        }
+        document = document.add(group);
+    }
+
+    document
+}
+
+fn polyline_data(points: &[[f32; 2]]) -> Data {
+    points[1..].iter().fold(
+        Data::new().move_to((points[0][0], points[0][1])),
+        |data, point| data.line_to((point[0], point[1])),
+    )
+}
+
+/// Makes a polyline draw itself from start to end, as fast as the particle moved along it.
+/// The dash offset goes from hiding the whole line to showing it, through keyframes at the
+/// fraction of the length covered at each point in time.
+fn draw_in(path: Path, points: &[[f32; 2]], times: &[f32], animation: &AnimationConfig) -> Path {
+    let mut covered = vec![0.0];
+    for segment in points.windows(2) {
+        let length = (segment[1][0] - segment[0][0]).hypot(segment[1][1] - segment[0][1]);
+        covered.push(covered[covered.len() - 1] + length);
+    }
+
+    let (length, start) = (covered[covered.len() - 1], times[0]);
+    let duration = times[times.len() - 1] - start;
+    if length <= 0.0 || duration <= 0.0 {
+        return path.set("opacity", 0).add(fade_in(start, animation));
+    }
+
+    let step = (points.len() + MAX_KEYFRAMES - 2) / (MAX_KEYFRAMES - 1);
+    let mut keyframes: Vec<usize> = (0..points.len()).step_by(step.max(1)).collect();
+    if keyframes[keyframes.len() - 1] != points.len() - 1 {
+        keyframes.push(points.len() - 1);
+    }
+
+    let key_times: Vec<String> = keyframes
+        .iter()
+        .map(|&i| ((times[i] - start) / duration).to_string())
+        .collect();
+    let values: Vec<String> = keyframes
+        .iter()
+        .map(|&i| (1.0 - covered[i] / length).to_string())
+        .collect();
+
+    path.set("pathLength", 1)
+        .set("stroke-dasharray", 1)
+        .set("stroke-dashoffset", 1)
+        .add(
+            Animate::new()
+                .set("attributeName", "stroke-dashoffset")
+                .set("begin", format!("{}s", start / animation.speed))
+                .set("dur", format!("{}s", duration / animation.speed))
+                .set("keyTimes", key_times.join(";"))
+                .set("values", values.join(";"))
+                .set("fill", "freeze"),
+        )
+}
+
+/// Quickly fades in an element at `time`. It should start out with an opacity of 0.
+fn fade_in(time: f32, animation: &AnimationConfig) -> Animate {
+    Animate::new()
+        .set("attributeName", "opacity")
+        .set("begin", format!("{}s", time / animation.speed))
+        .set("dur", format!("{}s", FADE_IN))
+        .set("from", 0)
+        .set("to", 1)
+        .set("fill", "freeze")
+}
+
+fn vertex_marker(position: [f32; 2], config: &VertexMarkerConfig) -> Path {
+    let (x, y) = (position[0], position[1]);
+    let half = config.size * 0.5;
+    let path = Path::new().set("class", "vertex");
+
+    match config.shape {
+        MarkerShape::Dot => path.set("fill", config.colour.as_str()).set(
+            "d",
+            Shape::Circle {
+                center: position,
+                radius: half,
+            }
+            .svg_data(),
+        ),
+        MarkerShape::Ring => path
+            .set("fill", "none")
+            .set("stroke", config.colour.as_str())
+            .set("stroke-width", config.size * 0.2)
+            .set(
+                "d",
+                Shape::Circle {
+                    center: position,
+                    radius: half * 0.8,
+                }
+                .svg_data(),
+            ),
+        MarkerShape::Cross => path
+            .set("fill", "none")
+            .set("stroke", config.colour.as_str())
+            .set("stroke-width", config.size * 0.2)
+            .set(
+                "d",
+                Data::new()
+                    .move_to((x - half, y - half))
+                    .line_to((x + half, y + half))
+                    .move_to((x - half, y + half))
+                    .line_to((x + half, y - half)),
+            ),
+        MarkerShape::Diamond => path.set("fill", config.colour.as_str()).set(
+            "d",
+            Shape::Polygon(vec![
+                [x, y - half],
+                [x + half, y],
+                [x, y + half],
+                [x - half, y],
+            ])
+            .svg_data(),
+        ),
+    }
+}
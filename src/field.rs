@@ -0,0 +1,265 @@
+use amethyst::core::math::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// A field made of a uniform part, analytic sources and sampled grids, all summed together.
+pub struct FieldMap {
+    pub uniform: Vector3<f32>,
+    pub sources: Vec<FieldSource>,
+    pub grids: Vec<FieldGrid>,
+}
+
+impl FieldMap {
+    pub fn at(&self, position: &Vector3<f32>) -> Vector3<f32> {
+        let analytic = self
+            .sources
+            .iter()
+            .fold(self.uniform, |total, source| total + source.at(position));
+
+        self.grids
+            .iter()
+            .fold(analytic, |total, grid| total + grid.at(position))
+    }
+}
+
+/// A spatially varying contribution to a field, summed on top of its uniform part.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum FieldSource {
+    /// Points away from `center` (towards it for a negative `strength`).
+    /// Equals `strength` at distance `radius` and falls off with `1/r^falloff`.
+    Radial {
+        center: Vector3<f32>,
+        strength: f32,
+        radius: f32,
+        falloff: f32,
+    },
+    /// Field of a magnetic dipole at `center`. On the dipole's equator at distance `radius`
+    /// it equals `-moment`, and it falls off with `1/r^3`.
+    Dipole {
+        center: Vector3<f32>,
+        moment: Vector3<f32>,
+        radius: f32,
+    },
+    /// Circles around `axis` through `center`, like the field around a current-carrying wire.
+    /// Equals `strength` at distance `radius` and falls off with `1/r`.
+    Vortex {
+        center: Vector3<f32>,
+        axis: Vector3<f32>,
+        strength: f32,
+        radius: f32,
+    },
+    /// Grows linearly along `direction`, from zero at `origin` to `field` at distance `length`.
+    Gradient {
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        field: Vector3<f32>,
+        length: f32,
+    },
+}
+
+impl FieldSource {
+    /// Checks for sources that would make the field infinite or NaN everywhere
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            FieldSource::Vortex { axis, .. } if axis.norm() == 0.0 => {
+                Err("a vortex needs an axis that isn't zero".to_string())
+            }
+            FieldSource::Gradient { direction, .. } if direction.norm() == 0.0 => {
+                Err("a gradient needs a direction that isn't zero".to_string())
+            }
+            FieldSource::Gradient { length, .. } if *length == 0.0 => {
+                Err("a gradient needs a length that isn't zero".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn at(&self, position: &Vector3<f32>) -> Vector3<f32> {
+        match self {
+            FieldSource::Radial {
+                center,
+                strength,
+                radius,
+                falloff,
+            } => {
+                let offset = position - center;
+                // Clamp the distance to avoid blowing up right at the center
+                let distance = offset.norm().max(1.0);
+
+                offset / distance * *strength * (radius / distance).powf(*falloff)
+            }
+            FieldSource::Dipole {
+                center,
+                moment,
+                radius,
+            } => {
+                let offset = position - center;
+                let distance = offset.norm().max(1.0);
+                let direction = offset / distance;
+
+                (direction * 3.0 * moment.dot(&direction) - moment) * (radius / distance).powi(3)
+            }
+            FieldSource::Vortex {
+                center,
+                axis,
+                strength,
+                radius,
+            } => {
+                let offset = position - center;
+                let distance = offset.norm().max(1.0);
+
+                axis.normalize().cross(&(offset / distance)) * *strength * (radius / distance)
+            }
+            FieldSource::Gradient {
+                origin,
+                direction,
+                field,
+                length,
+            } => {
+                let along = (position - origin).dot(&direction.normalize());
+                field * (along / length)
+            }
+        }
+    }
+}
+
+/// The contents of a field grid file: `columns * rows` samples in row-major order,
+/// starting at the grid's origin.
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct FieldGridData {
+    pub columns: usize,
+    pub rows: usize,
+    pub values: Vec<Vector3<f32>>,
+}
+
+/// A field sampled on a regular grid in the chamber plane, bilinearly interpolated between samples.
+/// Outside of the grid, it contributes nothing.
+pub struct FieldGrid {
+    pub origin: [f32; 2],
+    pub spacing: f32,
+    pub data: FieldGridData,
+}
+
+impl FieldGrid {
+    pub fn at(&self, position: &Vector3<f32>) -> Vector3<f32> {
+        let (columns, rows) = (self.data.columns, self.data.rows);
+        let x = (position[0] - self.origin[0]) / self.spacing;
+        let y = (position[1] - self.origin[1]) / self.spacing;
+
+        if columns < 2
+            || rows < 2
+            || x < 0.0
+            || y < 0.0
+            || x > (columns - 1) as f32
+            || y > (rows - 1) as f32
+        {
+            return Vector3::zeros();
+        }
+
+        // Clamp so samples on the far edges still have a neighbour to interpolate with
+        let col = (x as usize).min(columns - 2);
+        let row = (y as usize).min(rows - 2);
+        let (fx, fy) = (x - col as f32, y - row as f32);
+
+        let sample = |c: usize, r: usize| self.data.values[r * columns + c];
+
+        let bottom = sample(col, row) * (1.0 - fx) + sample(col + 1, row) * fx;
+        let top = sample(col, row + 1) * (1.0 - fx) + sample(col + 1, row + 1) * fx;
+
+        bottom * (1.0 - fy) + top * fy
+    }
+}
+
+/// How the strength of a field changes over the course of the simulation,
+/// as a factor applied to the whole field at simulation time `t`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum TimeVariation {
+    Constant,
+    /// Goes linearly from `from` at time `start` to `to` at time `end`, and holds outside of that.
+    Ramp {
+        start: f32,
+        end: f32,
+        from: f32,
+        to: f32,
+    },
+    /// `offset + amplitude * sin(2π * t / period + phase)`
+    Sine {
+        offset: f32,
+        amplitude: f32,
+        period: f32,
+        phase: f32,
+    },
+    /// `(time, factor)` pairs, sorted by time: the factor jumps to the new value at each time,
+    /// 1 before the first.
+    Steps(Vec<(f32, f32)>),
+    /// `(time, factor)` pairs, sorted by time, linearly interpolated and held constant before the
+    /// first and after the last.
+    Keyframes(Vec<(f32, f32)>),
+}
+
+impl Default for TimeVariation {
+    fn default() -> Self {
+        TimeVariation::Constant
+    }
+}
+
+impl TimeVariation {
+    /// Checks that steps and keyframes are in order, the lookups in `factor_at` rely on it
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            TimeVariation::Steps(pairs) | TimeVariation::Keyframes(pairs) => {
+                match pairs.windows(2).find(|pair| pair[0].0 > pair[1].0) {
+                    Some(pair) => Err(format!(
+                        "times must be sorted, but {} comes after {}",
+                        pair[1].0, pair[0].0
+                    )),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn factor_at(&self, t: f32) -> f32 {
+        match self {
+            TimeVariation::Constant => 1.0,
+            TimeVariation::Ramp {
+                start,
+                end,
+                from,
+                to,
+            } => {
+                if t <= *start {
+                    *from
+                } else if t >= *end {
+                    *to
+                } else {
+                    from + (to - from) * (t - start) / (end - start)
+                }
+            }
+            TimeVariation::Sine {
+                offset,
+                amplitude,
+                period,
+                phase,
+            } => offset + amplitude * (2.0 * std::f32::consts::PI * t / period + phase).sin(),
+            TimeVariation::Steps(steps) => steps
+                .iter()
+                .take_while(|(time, _)| *time <= t)
+                .last()
+                .map_or(1.0, |(_, factor)| *factor),
+            TimeVariation::Keyframes(keyframes) => {
+                let next = keyframes.iter().position(|(time, _)| *time > t);
+
+                match next {
+                    None => keyframes.last().map_or(1.0, |(_, factor)| *factor),
+                    Some(0) => keyframes[0].1,
+                    Some(i) => {
+                        let (t0, f0) = keyframes[i - 1];
+                        let (t1, f1) = keyframes[i];
+                        f0 + (f1 - f0) * (t - t0) / (t1 - t0)
+                    }
+                }
+            }
+        }
+    }
+}
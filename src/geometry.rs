@@ -0,0 +1,348 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use svg::node::element::path::Data;
+
+/// An outline in the chamber plane
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum Shape {
+    Rectangle { origin: [f32; 2], size: [f32; 2] },
+    Circle { center: [f32; 2], radius: f32 },
+    Ellipse { center: [f32; 2], radii: [f32; 2] },
+    Polygon(Vec<[f32; 2]>),
+}
+
+impl Shape {
+    /// Checks that the shape encloses an area: polygons need at least 3 vertices,
+    /// and no shape can be flat in either direction
+    pub fn validate(&self) -> Result<(), String> {
+        if let Shape::Polygon(vertices) = self {
+            if vertices.len() < 3 {
+                return Err(format!(
+                    "a polygon needs at least 3 vertices, this one has {}",
+                    vertices.len()
+                ));
+            }
+        }
+
+        let (min, max) = self.bounds();
+        if !(max[0] > min[0] && max[1] > min[1]) {
+            return Err(format!("{:?} has no area", self));
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, point: [f32; 2]) -> bool {
+        match self {
+            Shape::Polygon(vertices) => {
+                // Even-odd rule: count the edges crossed by a ray towards +x
+                let mut inside = false;
+                let mut j = vertices.len().wrapping_sub(1);
+
+                for (i, a) in vertices.iter().enumerate() {
+                    let b = vertices[j];
+                    if (a[1] > point[1]) != (b[1] > point[1])
+                        && point[0] < (b[0] - a[0]) * (point[1] - a[1]) / (b[1] - a[1]) + a[0]
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+
+                inside
+            }
+            Shape::Ellipse { .. } => self.ellipse_scale(point) <= 1.0,
+            _ => self.distance_outside(point) <= 0.0,
+        }
+    }
+
+    /// How far `point` lies outside of the shape, 0 if it's inside
+    pub fn distance_outside(&self, point: [f32; 2]) -> f32 {
+        match self {
+            Shape::Rectangle { origin, size } => {
+                let dx = (origin[0] - point[0])
+                    .max(point[0] - origin[0] - size[0])
+                    .max(0.0);
+                let dy = (origin[1] - point[1])
+                    .max(point[1] - origin[1] - size[1])
+                    .max(0.0);
+                dx.hypot(dy)
+            }
+            Shape::Circle { center, radius } => {
+                ((point[0] - center[0]).hypot(point[1] - center[1]) - radius).max(0.0)
+            }
+            Shape::Ellipse { .. } | Shape::Polygon(_) => {
+                if self.contains(point) {
+                    0.0
+                } else {
+                    let nearest = self.nearest_boundary_point(point);
+                    (point[0] - nearest[0]).hypot(point[1] - nearest[1])
+                }
+            }
+        }
+    }
+
+    /// The point on the outline closest to `point`. For ellipses, this is approximated
+    /// by the point where the line towards the center crosses the outline.
+    pub fn nearest_boundary_point(&self, point: [f32; 2]) -> [f32; 2] {
+        match self {
+            Shape::Rectangle { origin, size } => {
+                let clamped = [
+                    point[0].max(origin[0]).min(origin[0] + size[0]),
+                    point[1].max(origin[1]).min(origin[1] + size[1]),
+                ];
+                if clamped != point {
+                    return clamped;
+                }
+
+                // Inside: snap to the closest edge
+                let edges = [
+                    (point[0] - origin[0], [origin[0], point[1]]),
+                    (
+                        origin[0] + size[0] - point[0],
+                        [origin[0] + size[0], point[1]],
+                    ),
+                    (point[1] - origin[1], [point[0], origin[1]]),
+                    (
+                        origin[1] + size[1] - point[1],
+                        [point[0], origin[1] + size[1]],
+                    ),
+                ];
+                edges
+                    .iter()
+                    .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+                    .unwrap()
+                    .1
+            }
+            Shape::Circle { center, radius } => {
+                let (dx, dy) = (point[0] - center[0], point[1] - center[1]);
+                let distance = dx.hypot(dy).max(std::f32::EPSILON);
+                [
+                    center[0] + dx / distance * radius,
+                    center[1] + dy / distance * radius,
+                ]
+            }
+            Shape::Ellipse { center, .. } => {
+                let scale = self.ellipse_scale(point).max(std::f32::EPSILON);
+                [
+                    center[0] + (point[0] - center[0]) / scale,
+                    center[1] + (point[1] - center[1]) / scale,
+                ]
+            }
+            Shape::Polygon(vertices) => {
+                let mut best = vertices[0];
+                let mut best_distance = std::f32::INFINITY;
+
+                for (i, a) in vertices.iter().enumerate() {
+                    let b = vertices[(i + 1) % vertices.len()];
+                    let candidate = nearest_on_segment(point, *a, b);
+                    let distance = (point[0] - candidate[0]).hypot(point[1] - candidate[1]);
+                    if distance < best_distance {
+                        best = candidate;
+                        best_distance = distance;
+                    }
+                }
+
+                best
+            }
+        }
+    }
+
+    pub fn center(&self) -> [f32; 2] {
+        match self {
+            Shape::Rectangle { origin, size } => {
+                [origin[0] + size[0] * 0.5, origin[1] + size[1] * 0.5]
+            }
+            Shape::Circle { center, .. } | Shape::Ellipse { center, .. } => *center,
+            Shape::Polygon(vertices) => {
+                let sum = vertices
+                    .iter()
+                    .fold([0.0, 0.0], |sum, v| [sum[0] + v[0], sum[1] + v[1]]);
+                let n = vertices.len() as f32;
+                [sum[0] / n, sum[1] / n]
+            }
+        }
+    }
+
+    /// Bottom-left and top-right corners of the bounding box
+    pub fn bounds(&self) -> ([f32; 2], [f32; 2]) {
+        match self {
+            Shape::Rectangle { origin, size } => {
+                (*origin, [origin[0] + size[0], origin[1] + size[1]])
+            }
+            Shape::Circle { center, radius } => (
+                [center[0] - radius, center[1] - radius],
+                [center[0] + radius, center[1] + radius],
+            ),
+            Shape::Ellipse { center, radii } => (
+                [center[0] - radii[0], center[1] - radii[1]],
+                [center[0] + radii[0], center[1] + radii[1]],
+            ),
+            Shape::Polygon(vertices) => vertices.iter().fold(
+                (
+                    [std::f32::INFINITY, std::f32::INFINITY],
+                    [std::f32::NEG_INFINITY, std::f32::NEG_INFINITY],
+                ),
+                |(min, max), v| {
+                    (
+                        [min[0].min(v[0]), min[1].min(v[1])],
+                        [max[0].max(v[0]), max[1].max(v[1])],
+                    )
+                },
+            ),
+        }
+    }
+
+    /// A uniformly distributed point inside the shape
+    pub fn random_point<R: Rng>(&self, rng: &mut R) -> [f32; 2] {
+        let (min, max) = self.bounds();
+
+        for _ in 0..1000 {
+            let point = [rng.gen_range(min[0], max[0]), rng.gen_range(min[1], max[1])];
+            if self.contains(point) {
+                return point;
+            }
+        }
+
+        self.center()
+    }
+
+    /// The point where the ray from the center in `direction` leaves the shape,
+    /// grown by `margin`. Assumes every point of the shape can be seen from its center.
+    pub fn exit_point(&self, direction: [f32; 2], margin: f32) -> [f32; 2] {
+        let center = self.center();
+        let (min, max) = self.bounds();
+        let length = direction[0].hypot(direction[1]).max(std::f32::EPSILON);
+        let direction = [direction[0] / length, direction[1] / length];
+        let along = |t: f32| [center[0] + direction[0] * t, center[1] + direction[1] * t];
+
+        let mut inside = 0.0;
+        let mut outside = (max[0] - min[0]).hypot(max[1] - min[1]) + margin;
+        for _ in 0..32 {
+            let middle = (inside + outside) * 0.5;
+            if self.distance_outside(along(middle)) <= margin {
+                inside = middle;
+            } else {
+                outside = middle;
+            }
+        }
+
+        along(inside)
+    }
+
+    /// Splits a polyline into the pieces that lie inside the shape.
+    /// Segments that cross the outline are cut where they cross it.
+    pub fn clip_polyline(&self, points: &[[f32; 2]]) -> Vec<Vec<[f32; 2]>> {
+        let mut pieces = Vec::new();
+        let mut piece = Vec::new();
+        let mut was_inside = false;
+
+        for (i, point) in points.iter().enumerate() {
+            let inside = self.contains(*point);
+
+            if i > 0 && inside != was_inside {
+                piece.push(self.crossing(points[i - 1], *point));
+                if !inside {
+                    pieces.push(std::mem::replace(&mut piece, Vec::new()));
+                }
+            }
+            if inside {
+                piece.push(*point);
+            }
+            was_inside = inside;
+        }
+
+        pieces.push(piece);
+        pieces.retain(|piece| piece.len() > 1);
+
+        pieces
+    }
+
+    /// Where the segment from `a` to `b` crosses the outline, if one end lies inside and the
+    /// other outside
+    fn crossing(&self, a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+        let a_inside = self.contains(a);
+        let (mut from, mut to) = (0.0, 1.0);
+        let along = |t: f32| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t];
+
+        for _ in 0..24 {
+            let middle = (from + to) * 0.5;
+            if self.contains(along(middle)) == a_inside {
+                from = middle;
+            } else {
+                to = middle;
+            }
+        }
+
+        along((from + to) * 0.5)
+    }
+
+    pub fn svg_data(&self) -> Data {
+        match self {
+            Shape::Rectangle { origin, size } => Data::new()
+                .move_to((origin[0], origin[1]))
+                .line_to((origin[0] + size[0], origin[1]))
+                .line_to((origin[0] + size[0], origin[1] + size[1]))
+                .line_to((origin[0], origin[1] + size[1]))
+                .close(),
+            Shape::Circle { center, radius } => ellipse_data(*center, [*radius, *radius]),
+            Shape::Ellipse { center, radii } => ellipse_data(*center, *radii),
+            Shape::Polygon(vertices) => vertices[1..]
+                .iter()
+                .fold(
+                    Data::new().move_to((vertices[0][0], vertices[0][1])),
+                    |data, v| data.line_to((v[0], v[1])),
+                )
+                .close(),
+        }
+    }
+
+    /// `(x / rx)^2 + (y / ry)^2` relative to the center, square rooted:
+    /// below 1 inside the ellipse, above 1 outside.
+    fn ellipse_scale(&self, point: [f32; 2]) -> f32 {
+        match self {
+            Shape::Ellipse { center, radii } => {
+                ((point[0] - center[0]) / radii[0]).hypot((point[1] - center[1]) / radii[1])
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn nearest_on_segment(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    let (abx, aby) = (b[0] - a[0], b[1] - a[1]);
+    let length_squared = abx * abx + aby * aby;
+    if length_squared == 0.0 {
+        return a;
+    }
+
+    let t = (((point[0] - a[0]) * abx + (point[1] - a[1]) * aby) / length_squared)
+        .max(0.0)
+        .min(1.0);
+    [a[0] + abx * t, a[1] + aby * t]
+}
+
+fn ellipse_data(center: [f32; 2], radii: [f32; 2]) -> Data {
+    // Two half arcs, since a single arc can't start and end at the same point
+    Data::new()
+        .move_to((center[0] - radii[0], center[1]))
+        .elliptical_arc_to(vec![
+            radii[0],
+            radii[1],
+            0.0,
+            1.0,
+            0.0,
+            center[0] + radii[0],
+            center[1],
+        ])
+        .elliptical_arc_to(vec![
+            radii[0],
+            radii[1],
+            0.0,
+            1.0,
+            0.0,
+            center[0] - radii[0],
+            center[1],
+        ])
+        .close()
+}
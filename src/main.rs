@@ -1,18 +1,26 @@
 use amethyst::{
-    core::transform::TransformBundle,
+    core::{frame_limiter::FrameRateLimitStrategy, transform::TransformBundle, SystemExt},
+    input::{InputBundle, StringBindings},
     prelude::*,
-    renderer::{types::DefaultBackend, RenderFlat2D, RenderToWindow, RenderingBundle},
+    renderer::{
+        types::DefaultBackend, RenderDebugLines, RenderFlat2D, RenderToWindow, RenderingBundle,
+    },
+    ui::{RenderUi, UiBundle},
     utils::{application_root_dir, fps_counter::FpsCounterBundle},
 };
 
 mod bubblechamber;
 mod components;
 mod config;
+mod export;
+mod field;
+mod geometry;
 mod resources;
 mod systems;
 
 use crate::bubblechamber::BubbleChamber;
 use crate::config::SimulationConfig;
+use crate::resources::SimulationState;
 
 fn main() -> amethyst::Result<()> {
     amethyst::start_logger(Default::default());
@@ -25,58 +33,112 @@ fn main() -> amethyst::Result<()> {
 
     let simulation_config_path = resources_dir.join("sim_config.ron");
     let simulation_config = SimulationConfig::load(&simulation_config_path);
+    simulation_config
+        .validate()
+        .map_err(amethyst::Error::from_string)?;
 
-    let game_data = GameDataBuilder::default()
-        .with_bundle(TransformBundle::new())?
-        .with_bundle(
-            RenderingBundle::<DefaultBackend>::new()
-                .with_plugin(
-                    RenderToWindow::from_config_path(display_config_path)
-                        .with_clear([0.0, 0.0, 0.0, 1.0]),
-                )
-                .with_plugin(RenderFlat2D::default()),
-        )?
+    let headless = simulation_config.headless;
+
+    let mut game_data = GameDataBuilder::default().with_bundle(TransformBundle::new())?;
+    // Headless runs don't open a window
+    if headless.is_none() {
+        game_data = game_data
+            .with_bundle(InputBundle::<StringBindings>::new())?
+            .with_bundle(UiBundle::<StringBindings>::new())?
+            .with_bundle(
+                RenderingBundle::<DefaultBackend>::new()
+                    .with_plugin(
+                        RenderToWindow::from_config_path(display_config_path)
+                            .with_clear([0.0, 0.0, 0.0, 1.0]),
+                    )
+                    .with_plugin(RenderFlat2D::default())
+                    .with_plugin(RenderDebugLines::default())
+                    .with_plugin(RenderUi::default()),
+            )?;
+    }
+
+    let mut game_data = game_data
         // .with_bundle(FpsCounterBundle::default())?
         // .with(systems::LogFps, "log_fps", &[])
-        .with(systems::LifeTimeCounter, "lifetime_counter", &[])
-        .with(systems::MagneticForce, "magnetic_force", &[])
-        .with(systems::Exhaustion, "exhaustion", &[])
         .with(
-            systems::MoveByVelocity,
+            systems::SimulationClock {
+                fixed_step: headless.as_ref().map(|headless| headless.step),
+            },
+            "simulation_clock",
+            &[],
+        )
+        .with(
+            systems::LifeTimeCounter.pausable(SimulationState::Running),
+            "lifetime_counter",
+            &["simulation_clock"],
+        )
+        .with(
+            systems::LorentzForce.pausable(SimulationState::Running),
+            "lorentz_force",
+            &["simulation_clock"],
+        )
+        .with(
+            systems::Exhaustion.pausable(SimulationState::Running),
+            "exhaustion",
+            &["simulation_clock"],
+        )
+        .with(
+            systems::MoveByVelocity.pausable(SimulationState::Running),
             "move_by_velocity",
-            &["magnetic_force", "exhaustion"],
+            &["lorentz_force", "exhaustion"],
         )
         .with(
-            systems::ExpireLifetimes,
+            systems::ExpireLifetimes.pausable(SimulationState::Running),
             "expire_lifetimes",
             &["move_by_velocity"],
         )
         .with(
-            systems::ParticleSplitter,
+            systems::ParticleSplitter.pausable(SimulationState::Running),
             "particle_splitter",
             &["move_by_velocity"],
         )
         .with(
-            systems::TraceBuilder,
-            "svg_path_builder",
+            systems::DeltaRays.pausable(SimulationState::Running),
+            "delta_rays",
             &["particle_splitter"],
         )
         .with(
-            systems::PersistentTrail,
-            "persistent_trail",
-            &["particle_splitter"],
+            systems::TraceBuilder.pausable(SimulationState::Running),
+            "svg_path_builder",
+            &["particle_splitter", "delta_rays"],
         )
         .with(
-            systems::Cleanup,
+            systems::ChamberBoundary.pausable(SimulationState::Running),
+            "chamber_boundary",
+            &["svg_path_builder"],
+        )
+        .with(
+            systems::Cleanup.pausable(SimulationState::Running),
             "cleanup",
-            &["particle_splitter", "expire_lifetimes"],
+            &["particle_splitter", "expire_lifetimes", "chamber_boundary"],
         );
+    if headless.is_none() {
+        game_data = game_data.with(
+            systems::TrailRenderer::default().pausable(SimulationState::Running),
+            "trail_renderer",
+            &["chamber_boundary"],
+        );
+    }
+
+    let mut builder = Application::build(assets_dir, BubbleChamber::new(headless.clone()))
+        .expect("Failed to initialize");
+    if headless.is_some() {
+        // Simulate as fast as possible, there's nobody watching
+        builder = builder.with_frame_limit(FrameRateLimitStrategy::Unlimited, 0);
+    }
 
-    let mut game = Application::build(assets_dir, BubbleChamber)
-        .expect("Failed to initialize")
+    let mut game = builder
         .with_resource(simulation_config.chamber)
         .with_resource(simulation_config.magnetic_field)
+        .with_resource(simulation_config.electric_field)
         .with_resource(simulation_config.particles)
+        .with_resource(simulation_config.output)
+        .with_resource(simulation_config.trail)
         .build(game_data)
         .expect("Failed to build game");
 
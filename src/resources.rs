@@ -1,12 +1,163 @@
 use amethyst::core::math::Vector3;
 
+use crate::components::{LifeTime, Particle, Trace};
+use crate::field::{FieldMap, TimeVariation};
+
+/// The simulation's own clock, which doesn't have to keep up with the wall clock
+#[derive(Default)]
+pub struct SimulationTime {
+    /// Seconds simulated in the current frame
+    pub delta: f32,
+    /// Seconds simulated since the start
+    pub elapsed: f32,
+}
+
+/// Pausing, stepping and speeding up the simulation, from the keyboard
+pub struct SimulationControl {
+    pub paused: bool,
+    /// Advance by a single frame while paused
+    pub step: bool,
+    /// Simulated seconds per second
+    pub speed: f32,
+}
+
+impl Default for SimulationControl {
+    fn default() -> Self {
+        SimulationControl {
+            paused: false,
+            step: false,
+            speed: 1.0,
+        }
+    }
+}
+
+/// Whether the systems moving the simulation forward run this frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimulationState {
+    Running,
+    Paused,
+}
+
+impl Default for SimulationState {
+    fn default() -> Self {
+        SimulationState::Running
+    }
+}
+
 /// The global magnetic field
 pub struct MagneticField {
-    pub field: Vector3<f32>,
+    pub field: FieldMap,
+    pub variation: TimeVariation,
+    /// Changes made from the keyboard while the simulation runs
+    pub adjustment: FieldAdjustment,
+}
+
+impl MagneticField {
+    /// The field at `position`, `t` seconds into the simulation
+    pub fn at(&self, position: &Vector3<f32>, t: f32) -> Vector3<f32> {
+        self.adjustment
+            .apply(self.field.at(position) * self.variation.factor_at(t))
+    }
+}
+
+/// Scales a field by `strength`, after tilting it by `tilt` radians about the x axis.
+/// Tilting a field along z moves part of it into the chamber plane, where it no longer bends
+/// the tracks. The in-plane part would push particles out of the plane, but the chamber is
+/// flat: `LorentzForce` drops any force along z, so tracks stay in the plane of the camera.
+pub struct FieldAdjustment {
+    pub strength: f32,
+    pub tilt: f32,
+}
+
+impl Default for FieldAdjustment {
+    fn default() -> Self {
+        FieldAdjustment {
+            strength: 1.0,
+            tilt: 0.0,
+        }
+    }
+}
+
+impl FieldAdjustment {
+    pub fn apply(&self, field: Vector3<f32>) -> Vector3<f32> {
+        let (sin, cos) = self.tilt.sin_cos();
+
+        Vector3::new(
+            field[0],
+            field[1] * cos - field[2] * sin,
+            field[1] * sin + field[2] * cos,
+        ) * self.strength
+    }
+}
+
+/// The global electric field
+pub struct ElectricField {
+    pub field: FieldMap,
+}
+
+/// Randomly placed imperfections in the chamber, shared by the on-screen and SVG renders
+#[derive(Default)]
+pub struct BackgroundNoise {
+    /// Center and radius of each bubble
+    pub bubbles: Vec<([f32; 2], f32)>,
+    pub stray_tracks: Vec<Vec<[f32; 2]>>,
+    /// Center, radius and opacity of each layer of haze
+    pub haze: Vec<([f32; 2], f32, f32)>,
+}
+
+/// What's known about a particle once it's been cleaned up
+#[derive(Clone)]
+pub struct ParticleRecord {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub generation: usize,
+    pub charges: [usize; 3],
+    pub mass: usize,
+    /// The lifetime the particle was given, after which it decays
+    pub decay_time: f32,
+    pub delta_ray: bool,
+}
+
+impl ParticleRecord {
+    pub fn new(particle: &Particle, lifetime: &LifeTime) -> ParticleRecord {
+        ParticleRecord {
+            id: particle.id,
+            parent: particle.parent,
+            generation: particle.generation,
+            charges: particle.charges,
+            mass: particle.mass,
+            decay_time: lifetime.decays_after,
+            delta_ray: particle.delta_ray,
+        }
+    }
+
+    pub fn total_charge(&self) -> isize {
+        self.charges[0] as isize - self.charges[2] as isize
+    }
+}
+
+/// Every particle that's been cleaned up so far
+#[derive(Default)]
+pub struct ParticleLog {
+    pub particles: Vec<ParticleRecord>,
+}
+
+/// A particle decaying into its daughters
+pub struct DecayVertex {
+    pub position: [f32; 2],
+    pub time: f32,
+    pub parent: usize,
+    pub daughters: Vec<usize>,
+}
+
+/// Every decay so far
+#[derive(Default)]
+pub struct DecayVertices {
+    pub vertices: Vec<DecayVertex>,
 }
 
 pub struct SVGBuilder {
-    pub paths: Vec<Vec<[f32; 2]>>,
+    pub paths: Vec<Trace>,
 }
 
 impl Default for SVGBuilder {
@@ -0,0 +1,115 @@
+use amethyst::core::Transform;
+use amethyst::ecs::{Entities, Join, Read, ReadStorage, System, Write, WriteStorage};
+
+use crate::components::{DeleteFlag, Particle, Trace, Velocity};
+use crate::config::{BoundaryMode, ChamberConfig};
+use crate::geometry::Shape;
+use crate::resources::{SVGBuilder, SimulationTime};
+
+pub struct ChamberBoundary;
+
+impl<'s> System<'s> for ChamberBoundary {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Particle>,
+        WriteStorage<'s, Transform>,
+        WriteStorage<'s, Velocity>,
+        WriteStorage<'s, Trace>,
+        WriteStorage<'s, DeleteFlag>,
+        Read<'s, ChamberConfig>,
+        Write<'s, SVGBuilder>,
+        Read<'s, SimulationTime>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            particles,
+            mut transforms,
+            mut velocities,
+            mut traces,
+            mut deletes,
+            chamber,
+            mut svgbuilder,
+            time,
+        ): Self::SystemData,
+    ) {
+        if chamber.boundary == BoundaryMode::Open {
+            return;
+        }
+
+        let outline = chamber.outline();
+        let margin = chamber.margin;
+
+        for (entity, _particle, transform, velocity) in
+            (&entities, &particles, &mut transforms, &mut velocities).join()
+        {
+            let mut position = *transform.translation();
+            let point = [position[0], position[1]];
+            let distance = outline.distance_outside(point);
+
+            if distance <= margin {
+                continue;
+            }
+
+            match chamber.boundary {
+                BoundaryMode::Open => {}
+                BoundaryMode::Absorb => {
+                    deletes
+                        .insert(entity, DeleteFlag {})
+                        .expect("Entity was already marked for deletion!");
+                }
+                BoundaryMode::Reflect => {
+                    let edge = outline.nearest_boundary_point(point);
+                    let normal = [
+                        (point[0] - edge[0]) / distance,
+                        (point[1] - edge[1]) / distance,
+                    ];
+
+                    // Mirror the overshoot back inside and bounce off the boundary
+                    let depth = distance - margin;
+                    position[0] -= 2.0 * depth * normal[0];
+                    position[1] -= 2.0 * depth * normal[1];
+                    transform.set_translation(position);
+
+                    let outward_speed = velocity.v[0] * normal[0] + velocity.v[1] * normal[1];
+                    if outward_speed > 0.0 {
+                        velocity.v[0] -= 2.0 * outward_speed * normal[0];
+                        velocity.v[1] -= 2.0 * outward_speed * normal[1];
+                    }
+                }
+                BoundaryMode::Wrap => {
+                    match &outline {
+                        Shape::Rectangle { origin, size } => {
+                            for i in 0..2 {
+                                let min = origin[i] - margin;
+                                let extent = size[i] + 2.0 * margin;
+                                position[i] = min + (position[i] - min).rem_euclid(extent);
+                            }
+                        }
+                        _ => {
+                            let center = outline.center();
+                            let entry = outline
+                                .exit_point([center[0] - point[0], center[1] - point[1]], margin);
+                            position[0] = entry[0];
+                            position[1] = entry[1];
+                        }
+                    }
+                    transform.set_translation(position);
+
+                    // Jumping across the chamber would draw a line through it,
+                    // so finalise the trace so far and start a new one.
+                    if let Some(trace) = traces.get_mut(entity) {
+                        let restarted = trace.restart(
+                            [position[0], position[1]],
+                            [velocity.v[0], velocity.v[1]],
+                            time.elapsed,
+                        );
+                        svgbuilder.paths.push(std::mem::replace(trace, restarted));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::components::{DeleteFlag, LifeTime, Particle, Trace};
-use crate::resources::SVGBuilder;
+use crate::resources::{ParticleLog, ParticleRecord, SVGBuilder};
 use amethyst::ecs::{Entities, Join, ReadStorage, System, Write, WriteStorage};
 
 pub struct ExpireLifetimes;
@@ -32,15 +32,28 @@ pub struct Cleanup;
 impl<'s> System<'s> for Cleanup {
     type SystemData = (
         Entities<'s>,
+        ReadStorage<'s, Particle>,
+        ReadStorage<'s, LifeTime>,
         ReadStorage<'s, Trace>,
         ReadStorage<'s, DeleteFlag>,
         Write<'s, SVGBuilder>,
+        Write<'s, ParticleLog>,
     );
 
-    fn run(&mut self, (entities, traces, deletes, mut svgbuilder): Self::SystemData) {
+    fn run(
+        &mut self,
+        (entities, particles, lifetimes, traces, deletes, mut svgbuilder, mut particle_log): Self::SystemData,
+    ) {
         // For each entity that has a trace and is about to be deleted, save the trace:
         for (trace, _del) in (&traces, &deletes).join() {
-            svgbuilder.paths.push(trace.points.clone());
+            svgbuilder.paths.push(trace.clone());
+        }
+
+        // And remember the particle itself
+        for (particle, lifetime, _del) in (&particles, &lifetimes, &deletes).join() {
+            particle_log
+                .particles
+                .push(ParticleRecord::new(particle, lifetime));
         }
 
         // Perform the actual delete
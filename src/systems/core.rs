@@ -1,17 +1,58 @@
 use crate::components::LifeTime;
+use crate::config::ChamberConfig;
+use crate::resources::{SimulationControl, SimulationState, SimulationTime};
 use amethyst::core::timing::Time;
-use amethyst::ecs::{Join, Read, System, WriteStorage};
+use amethyst::core::Transform;
+use amethyst::ecs::{Join, Read, ReadStorage, System, Write, WriteStorage};
 use amethyst::utils::fps_counter::FpsCounter;
 use log::info;
 
+/// Advances the simulation time, by how long the last frame took or by a fixed step,
+/// scaled by the simulation speed. Decides whether the rest of the simulation runs this frame.
+pub struct SimulationClock {
+    pub fixed_step: Option<f32>,
+}
+
+impl<'s> System<'s> for SimulationClock {
+    type SystemData = (
+        Write<'s, SimulationTime>,
+        Write<'s, SimulationControl>,
+        Write<'s, SimulationState>,
+        Read<'s, Time>,
+    );
+
+    fn run(&mut self, (mut simulation_time, mut control, mut state, time): Self::SystemData) {
+        if control.paused && !control.step {
+            *state = SimulationState::Paused;
+            simulation_time.delta = 0.0;
+            return;
+        }
+        control.step = false;
+
+        let delta = self.fixed_step.unwrap_or_else(|| time.delta_seconds()) * control.speed;
+
+        *state = SimulationState::Running;
+        simulation_time.delta = delta;
+        simulation_time.elapsed += delta;
+    }
+}
+
 pub struct LifeTimeCounter;
 
 impl<'s> System<'s> for LifeTimeCounter {
-    type SystemData = (WriteStorage<'s, LifeTime>, Read<'s, Time>);
+    type SystemData = (
+        WriteStorage<'s, LifeTime>,
+        ReadStorage<'s, Transform>,
+        Read<'s, ChamberConfig>,
+        Read<'s, SimulationTime>,
+    );
+
+    fn run(&mut self, (mut lifetimes, transforms, chamber, time): Self::SystemData) {
+        for (lifetime, transform) in (&mut lifetimes, &transforms).join() {
+            let position = transform.translation();
+            let density = chamber.density_at([position[0], position[1]]);
 
-    fn run(&mut self, (mut lifetimes, time): Self::SystemData) {
-        for lifetime in (&mut lifetimes).join() {
-            lifetime.t += time.delta_seconds();
+            lifetime.t += time.delta * density;
         }
     }
 }
@@ -0,0 +1,136 @@
+use amethyst::core::math::Vector3;
+use amethyst::core::Transform;
+use amethyst::ecs::{Entities, Join, Read, System, WriteStorage};
+use amethyst::renderer::{SpriteRender, Transparent};
+use rand::distributions::{Distribution, Poisson};
+use rand::thread_rng;
+use rand::Rng;
+
+use crate::components::{LifeTime, Particle, Trace, Velocity};
+use crate::config::MultiParticlesConfig;
+use crate::resources::SimulationTime;
+
+/// Spawns knock-on electrons along the tracks of charged particles.
+/// Only particles heavier than a single charge produce them, so they don't cascade.
+pub struct DeltaRays;
+
+impl<'s> System<'s> for DeltaRays {
+    type SystemData = (
+        Entities<'s>,
+        WriteStorage<'s, Particle>,
+        WriteStorage<'s, LifeTime>,
+        WriteStorage<'s, Transform>,
+        WriteStorage<'s, Velocity>,
+        WriteStorage<'s, SpriteRender>,
+        WriteStorage<'s, Transparent>,
+        WriteStorage<'s, Trace>,
+        Read<'s, MultiParticlesConfig>,
+        Read<'s, SimulationTime>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut particles,
+            mut lifetimes,
+            mut transforms,
+            mut velocities,
+            mut sprites,
+            mut transparents,
+            mut traces,
+            particles_config,
+            time,
+        ): Self::SystemData,
+    ) {
+        let config = match &particles_config.delta_rays {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut rng = thread_rng();
+        let mut new_electrons = Vec::new();
+
+        for (entity, particle, transform, velocity, trace) in
+            (&entities, &particles, &transforms, &velocities, &traces).join()
+        {
+            // Delta rays don't knock out electrons of their own, or they'd cascade
+            if particle.delta_ray || particle.total_charge == 0 {
+                continue;
+            }
+
+            let path_length = velocity.v.norm() * time.delta;
+            let expected = config.rate * particle.total_charge.abs() as f32 * path_length;
+            if expected <= 0.0 {
+                continue;
+            }
+
+            let count = Poisson::new(expected as f64).sample(&mut rng);
+            if count == 0 {
+                continue;
+            }
+
+            let previous = trace.points[trace.points.len() - 1];
+            let current = transform.translation();
+            let direction = velocity.v.normalize();
+
+            for _ in 0..count {
+                // Somewhere along the distance travelled since the last trace point
+                let along: f32 = rng.gen();
+                let location = Vector3::new(
+                    previous[0] + (current[0] - previous[0]) * along,
+                    previous[1] + (current[1] - previous[1]) * along,
+                    current[2],
+                );
+
+                // Kicked out forwards, at an angle between 30 and 90 degrees to either side
+                let angle =
+                    rng.gen_range(30f32, 90f32).to_radians() * if rng.gen() { 1.0 } else { -1.0 };
+                let (sin, cos) = angle.sin_cos();
+                let kick = Vector3::new(
+                    direction[0] * cos - direction[1] * sin,
+                    direction[0] * sin + direction[1] * cos,
+                    0.0,
+                );
+                let speed = if config.max_speed > config.min_speed {
+                    rng.gen_range(config.min_speed, config.max_speed)
+                } else {
+                    config.min_speed
+                };
+
+                new_electrons.push((
+                    Particle::new([0, 0, 1]).knocked_out_by(particle),
+                    location,
+                    kick * speed,
+                    sprites.get(entity).cloned(),
+                ));
+            }
+        }
+
+        for (electron, location, velocity, sprite) in new_electrons {
+            let mut transform = Transform::default();
+            transform.set_translation(location);
+            let trace = Trace::new(
+                &electron,
+                [location[0], location[1]],
+                [velocity[0], velocity[1]],
+                time.elapsed,
+            );
+
+            let mut entity = entities
+                .build_entity()
+                .with(electron, &mut particles)
+                .with(LifeTime::new(config.lifetime), &mut lifetimes)
+                .with(transform, &mut transforms)
+                .with(Velocity { v: velocity }, &mut velocities)
+                .with(trace, &mut traces)
+                .with(Transparent, &mut transparents);
+
+            if let Some(sprite) = sprite {
+                entity = entity.with(sprite, &mut sprites);
+            }
+
+            entity.build();
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use amethyst::core::timing::Time;
 use amethyst::core::Transform;
 use amethyst::ecs::{Join, Read, ReadExpect, ReadStorage, System, WriteStorage};
 
 use crate::components::{Particle, Velocity};
-use crate::resources::MagneticField;
+use crate::config::ChamberConfig;
+use crate::resources::{ElectricField, MagneticField, SimulationTime};
 
 pub struct MoveByVelocity;
 
@@ -12,36 +12,50 @@ impl<'s> System<'s> for MoveByVelocity {
         ReadStorage<'s, Particle>,
         ReadStorage<'s, Velocity>,
         WriteStorage<'s, Transform>,
-        Read<'s, Time>,
+        Read<'s, SimulationTime>,
     );
 
     fn run(&mut self, (particles, velocities, mut transforms, time): Self::SystemData) {
         for (_particle, velocity, transform) in (&particles, &velocities, &mut transforms).join() {
-            let movements = velocity.v * time.delta_seconds();
+            let movements = velocity.v * time.delta;
             transform.prepend_translation(movements);
         }
     }
 }
 
-pub struct MagneticForce;
+pub struct LorentzForce;
 
-impl<'s> System<'s> for MagneticForce {
+impl<'s> System<'s> for LorentzForce {
     type SystemData = (
         ReadStorage<'s, Particle>,
+        ReadStorage<'s, Transform>,
         WriteStorage<'s, Velocity>,
         ReadExpect<'s, MagneticField>,
-        Read<'s, Time>,
+        ReadExpect<'s, ElectricField>,
+        Read<'s, SimulationTime>,
     );
 
-    fn run(&mut self, (particles, mut velocities, magnetic_field, time): Self::SystemData) {
-        for (particle, velocity) in (&particles, &mut velocities).join() {
-            // Magnetic component of Lorentz force:
-            let force = particle.total_charge as f32 * (velocity.v.cross(&magnetic_field.field));
+    fn run(
+        &mut self,
+        (particles, transforms, mut velocities, magnetic_field, electric_field, time): Self::SystemData,
+    ) {
+        let now = time.elapsed;
+
+        for (particle, transform, velocity) in (&particles, &transforms, &mut velocities).join() {
+            let charge = particle.total_charge as f32;
+            let position = transform.translation();
+            let electric = electric_field.field.at(position);
+            let magnetic = magnetic_field.at(position, now);
+
+            // Lorentz force: F = q(E + v x B)
+            let mut force = charge * (electric + velocity.v.cross(&magnetic));
+            // The chamber is flat, so anything pushing particles out of it is ignored
+            force[2] = 0.0;
 
             // (F = m.a), so (a = F/m)
             let acceleration = force / particle.mass as f32;
 
-            velocity.v += acceleration * time.delta_seconds();
+            velocity.v += acceleration * time.delta;
         }
     }
 }
@@ -49,9 +63,14 @@ impl<'s> System<'s> for MagneticForce {
 pub struct Exhaustion;
 
 impl<'s> System<'s> for Exhaustion {
-    type SystemData = (WriteStorage<'s, Velocity>, Read<'s, Time>);
+    type SystemData = (
+        WriteStorage<'s, Velocity>,
+        ReadStorage<'s, Transform>,
+        Read<'s, ChamberConfig>,
+        Read<'s, SimulationTime>,
+    );
 
-    fn run(&mut self, (mut velocities, time): Self::SystemData) {
+    fn run(&mut self, (mut velocities, transforms, chamber, time): Self::SystemData) {
         // It is known that a charged particle’s range d,
         // which is the distance it travelled beforecoming to rest,
         // is approximately proportional to the fourth power of its initial momentum,
@@ -60,9 +79,13 @@ impl<'s> System<'s> for Exhaustion {
         // Normal drag is proportional to velocity or velocity squared,
         // So it seems velocity is always involved...
 
-        for velocity in (&mut velocities).join() {
-            // Simulate some simple friction
-            velocity.v *= 1.0 - (0.3 * time.delta_seconds());
+        for (velocity, transform) in (&mut velocities, &transforms).join() {
+            let position = transform.translation();
+            let density = chamber.density_at([position[0], position[1]]);
+
+            // Simulate some simple friction, stronger in denser materials.
+            // Decaying exponentially keeps dense materials from reversing the particle.
+            velocity.v *= (-0.3 * density * time.delta).exp();
         }
     }
 }
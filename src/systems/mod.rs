@@ -1,11 +1,15 @@
+mod boundary;
 mod cleanup;
 mod core;
+mod delta_rays;
 mod forces;
 mod splitter;
 mod trace;
 
+pub use self::boundary::ChamberBoundary;
 pub use self::cleanup::{Cleanup, ExpireLifetimes};
-pub use self::core::{LifeTimeCounter, LogFps};
-pub use self::forces::{Exhaustion, MagneticForce, MoveByVelocity};
+pub use self::core::{LifeTimeCounter, LogFps, SimulationClock};
+pub use self::delta_rays::DeltaRays;
+pub use self::forces::{Exhaustion, LorentzForce, MoveByVelocity};
 pub use self::splitter::ParticleSplitter;
-pub use self::trace::{PersistentTrail, TraceBuilder};
+pub use self::trace::{TraceBuilder, TrailRenderer};
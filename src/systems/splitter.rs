@@ -1,6 +1,6 @@
 use amethyst::core::Hidden;
 use amethyst::core::Transform;
-use amethyst::ecs::{Entities, Join, Read, System, WriteStorage};
+use amethyst::ecs::{Entities, Join, Read, System, Write, WriteStorage};
 use amethyst::renderer::{SpriteRender, Transparent};
 // use log::info;
 use rand::distributions::{Distribution, Exp};
@@ -8,7 +8,8 @@ use rand::thread_rng;
 use rand::Rng;
 
 use crate::components::{DeleteFlag, LifeTime, Particle, Trace, Velocity};
-use crate::config::MultiParticlesConfig;
+use crate::config::{MultiParticlesConfig, OutputConfig};
+use crate::resources::{DecayVertex, DecayVertices, SimulationTime};
 
 pub struct ParticleSplitter;
 
@@ -25,6 +26,9 @@ impl<'s> System<'s> for ParticleSplitter {
         WriteStorage<'s, Trace>,
         WriteStorage<'s, DeleteFlag>,
         Read<'s, MultiParticlesConfig>,
+        Read<'s, SimulationTime>,
+        Write<'s, DecayVertices>,
+        Read<'s, OutputConfig>,
     );
 
     fn run(
@@ -41,6 +45,9 @@ impl<'s> System<'s> for ParticleSplitter {
             mut traces,
             mut deletes,
             particles_config,
+            time,
+            mut decay_vertices,
+            output,
         ): Self::SystemData,
     ) {
         let mut new_particles = Vec::new();
@@ -51,7 +58,7 @@ impl<'s> System<'s> for ParticleSplitter {
             &lifetimes,
             &transforms,
             &velocities,
-            &sprites,
+            sprites.maybe(),
         )
             .join()
         {
@@ -62,8 +69,17 @@ impl<'s> System<'s> for ParticleSplitter {
             deletes
                 .insert(entity, DeleteFlag {})
                 .expect("Entity was already marked for deletion!");
-            new_particles
-                .append(&mut self.split_particle(&particle, &transform, &velocity, &sprite));
+            let mut daughters = self.split_particle(&particle, &transform, &velocity, sprite);
+
+            let position = transform.translation();
+            decay_vertices.vertices.push(DecayVertex {
+                position: [position[0], position[1]],
+                time: time.elapsed,
+                parent: particle.id,
+                daughters: daughters.iter().map(|daughter| daughter.0.id).collect(),
+            });
+
+            new_particles.append(&mut daughters);
         }
 
         let decay_rate = particles_config.decay_rate;
@@ -74,6 +90,12 @@ impl<'s> System<'s> for ParticleSplitter {
         for (particle, transform, velocity, sprite) in new_particles {
             let total_charge = particle.total_charge;
             let location = transform.translation();
+            let trace = Trace::new(
+                &particle,
+                [location[0], location[1]],
+                [velocity.v[0], velocity.v[1]],
+                time.elapsed,
+            );
 
             let mut entity = entities
                 .build_entity()
@@ -84,14 +106,14 @@ impl<'s> System<'s> for ParticleSplitter {
                 )
                 .with(transform.clone(), &mut transforms)
                 .with(velocity, &mut velocities)
-                .with(sprite.clone(), &mut sprites)
                 .with(Transparent, &mut transparents);
 
-            if total_charge != 0 {
-                entity = entity.with(
-                    Trace::new(location[0], location[1]),
-                    &mut traces,
-                );
+            if let Some(sprite) = sprite {
+                entity = entity.with(sprite, &mut sprites);
+            }
+
+            if total_charge != 0 || output.ghost_tracks.is_some() {
+                entity = entity.with(trace, &mut traces);
             }
 
             if total_charge == 0 {
@@ -110,8 +132,8 @@ impl ParticleSplitter {
         particle: &Particle,
         transform: &Transform,
         velocity: &Velocity,
-        sprite: &SpriteRender,
-    ) -> Vec<(Particle, Transform, Velocity, SpriteRender)> {
+        sprite: Option<&SpriteRender>,
+    ) -> Vec<(Particle, Transform, Velocity, Option<SpriteRender>)> {
         let mut random = rand::thread_rng();
 
         // let mut n_new_parts = Poisson::new(2.0).sample(&mut random) as u8;
@@ -152,10 +174,10 @@ impl ParticleSplitter {
             charges_left[2] -= neg;
 
             results.push((
-                Particle::new([pos, neutral, neg]),
+                Particle::new([pos, neutral, neg]).child_of(particle),
                 transform.clone(),
                 velocity.clone(),
-                sprite.clone(),
+                sprite.cloned(),
             ));
         }
 
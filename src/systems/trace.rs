@@ -1,49 +1,309 @@
-use crate::components::Trace;
+use crate::components::{Trace, Velocity};
+use crate::config::{ChamberConfig, TrailMode};
+use crate::export::Canvas;
+use crate::resources::SimulationTime;
+use amethyst::assets::{AssetStorage, Handle, Loader};
+use amethyst::core::math::{Point3, Vector3};
 use amethyst::core::Transform;
-use amethyst::ecs::{Entities, Join, ReadStorage, System, WriteStorage};
-use amethyst::renderer::{SpriteRender, Transparent};
+use amethyst::ecs::{
+    Entities, Entity, Join, Read, ReadExpect, ReadStorage, System, Write, WriteStorage,
+};
+use amethyst::renderer::{
+    debug_drawing::{DebugLinesComponent, DebugLinesParams},
+    palette::Srgba,
+    rendy::{
+        hal::image::{Kind, ViewKind},
+        texture::{pixel::Rgba8Srgb, TextureBuilder},
+    },
+    resources::Tint,
+    types::TextureData,
+    Sprite, SpriteRender, SpriteSheet, Texture, Transparent,
+};
+use amethyst::window::ScreenDimensions;
+
+/// Width of the trails in chamber units, as wide as the dots they used to be drawn with
+const TRAIL_WIDTH: f32 = 8.0;
+/// Trails are drawn as lines until there are this many segments of them, then they're baked
+/// into the trail texture. The lines get uploaded every frame, the texture only when it changes.
+const BAKE_AFTER: usize = 4096;
+/// The trail texture is scaled down to at most this many pixels
+const MAX_TRAIL_PIXELS: f32 = 4_000_000.0;
+
+/// A piece of trail, with the time it was left
+type Segment = ([f32; 2], [f32; 2], f32);
 
 pub struct TraceBuilder;
 
 impl<'s> System<'s> for TraceBuilder {
-    type SystemData = (ReadStorage<'s, Transform>, WriteStorage<'s, Trace>);
+    type SystemData = (
+        ReadStorage<'s, Transform>,
+        ReadStorage<'s, Velocity>,
+        WriteStorage<'s, Trace>,
+        Read<'s, SimulationTime>,
+    );
 
-    fn run(&mut self, (transforms, mut traces): Self::SystemData) {
-        for (transform, trace) in (&transforms, &mut traces).join() {
+    fn run(&mut self, (transforms, velocities, mut traces, time): Self::SystemData) {
+        let now = time.elapsed;
+
+        for (transform, velocity, trace) in (&transforms, &velocities, &mut traces).join() {
             let trans = transform.translation();
-            trace.points.push([trans[0], trans[1]]);
+            trace.push([trans[0], trans[1]], [velocity.v[0], velocity.v[1]], now);
         }
     }
 }
 
-pub struct PersistentTrail;
+/// Draws the trails of charged particles on screen. The newest pieces are lines, all held by a
+/// single entity, and every so often they get baked into a texture covering the chamber, so
+/// the lines never pile up.
+/// Fading trails fade on the texture through its tint, which gets folded into the texture
+/// at the next bake.
+#[derive(Default)]
+pub struct TrailRenderer {
+    /// The entity holding the lines, created on the first run
+    lines: Option<Entity>,
+    /// Segments that aren't on the shown trail texture yet, oldest first
+    recent: Vec<Segment>,
+    /// The trail texture, created on the first run
+    baked: Option<BakedTrails>,
+}
+
+/// Trails drawn onto a canvas that's shown as a single sprite
+struct BakedTrails {
+    canvas: Canvas,
+    width: u32,
+    height: u32,
+    entity: Entity,
+    /// When the canvas was last drawn onto
+    baked_at: f32,
+    /// When the shown texture was baked
+    shown_at: f32,
+    /// A new texture that's still loading, and how many of the recent segments it holds
+    pending: Option<(Handle<SpriteSheet>, usize)>,
+}
 
-impl<'s> System<'s> for PersistentTrail {
+impl<'s> System<'s> for TrailRenderer {
     type SystemData = (
         Entities<'s>,
-        WriteStorage<'s, Transform>,
         ReadStorage<'s, Trace>,
+        WriteStorage<'s, DebugLinesComponent>,
+        WriteStorage<'s, Transform>,
         WriteStorage<'s, SpriteRender>,
         WriteStorage<'s, Transparent>,
+        WriteStorage<'s, Tint>,
+        Write<'s, DebugLinesParams>,
+        ReadExpect<'s, Loader>,
+        Read<'s, AssetStorage<Texture>>,
+        Read<'s, AssetStorage<SpriteSheet>>,
+        ReadExpect<'s, ScreenDimensions>,
+        Read<'s, ChamberConfig>,
+        Read<'s, TrailMode>,
+        Read<'s, SimulationTime>,
     );
 
     fn run(
         &mut self,
-        (entities, mut transforms, traces, mut sprites, mut transparents): Self::SystemData,
+        (
+            entities,
+            traces,
+            mut debug_lines,
+            mut transforms,
+            mut sprites,
+            mut transparents,
+            mut tints,
+            mut line_params,
+            loader,
+            textures,
+            sprite_sheets,
+            screen,
+            chamber,
+            trail_mode,
+            time,
+        ): Self::SystemData,
     ) {
-        let mut to_create = Vec::new();
+        let now = time.elapsed;
+        let opacity = |left_at: f32| match *trail_mode {
+            TrailMode::Persistent => 1.0,
+            TrailMode::Fading { half_life } => 0.5f32.powf((now - left_at) / half_life),
+        };
+
+        let entity = match self.lines {
+            Some(entity) => entity,
+            None => {
+                let entity = entities
+                    .build_entity()
+                    .with(DebugLinesComponent::with_capacity(1024), &mut debug_lines)
+                    .build();
+                self.lines = Some(entity);
+                entity
+            }
+        };
+        let lines = debug_lines
+            .get_mut(entity)
+            .expect("The trail lines went missing");
+
+        let baked = match &mut self.baked {
+            Some(baked) => baked,
+            None => {
+                let baked = BakedTrails::new(&entities, &mut transforms, &chamber, now);
+                transparents
+                    .insert(baked.entity, Transparent)
+                    .expect("Failed to create the trail texture");
+                self.baked.get_or_insert(baked)
+            }
+        };
 
-        for (transform, _trace, sprite) in (&transforms, &traces, &sprites).join() {
-            to_create.push((transform.clone(), sprite.clone()));
+        // Neutral particles are only traced for their ghost tracks
+        self.recent.extend(
+            (&traces)
+                .join()
+                .filter(|trace| trace.charge != 0 && trace.points.len() > 1)
+                .map(|trace| {
+                    let n = trace.points.len();
+                    (trace.points[n - 2], trace.points[n - 1], now)
+                }),
+        );
+
+        // Only swap textures once the new one can be shown, or the trails would flicker
+        let loaded = baked.pending.as_ref().map_or(false, |(sheet, _)| {
+            sprite_sheets
+                .get(sheet)
+                .map_or(false, |sheet| textures.get(&sheet.texture).is_some())
+        });
+        if loaded {
+            let (sheet, baked_segments) = baked.pending.take().unwrap();
+            sprites
+                .insert(
+                    baked.entity,
+                    SpriteRender {
+                        sprite_sheet: sheet,
+                        sprite_number: 0,
+                    },
+                )
+                .expect("Failed to show the trail texture");
+            baked.shown_at = baked.baked_at;
+            self.recent.drain(..baked_segments);
         }
 
-        for (loc, sprite) in to_create {
-            entities
-                .build_entity()
-                .with(loc, &mut transforms)
-                .with(Transparent, &mut transparents)
-                .with(sprite.clone(), &mut sprites)
-                .build();
+        if baked.pending.is_none() && self.recent.len() >= BAKE_AFTER {
+            let sheet = baked.bake(&self.recent, opacity, &loader, &textures, &sprite_sheets);
+            baked.baked_at = now;
+            baked.pending = Some((sheet, self.recent.len()));
+        }
+
+        tints
+            .insert(
+                baked.entity,
+                Tint(Srgba::new(1.0, 1.0, 1.0, opacity(baked.shown_at))),
+            )
+            .expect("Failed to fade the trail texture");
+
+        // Line widths are in pixels of the window, which the camera stretches the chamber over.
+        // The screen dimensions are physical pixels once the window has been set up.
+        line_params.line_width = TRAIL_WIDTH * screen.width() / chamber.width;
+
+        // There are never many lines, so they're simply all redrawn
+        lines.clear();
+        for (start, end, left_at) in &self.recent {
+            lines.add_line(
+                point(*start),
+                point(*end),
+                Srgba::new(1.0, 1.0, 1.0, opacity(*left_at)),
+            );
+        }
+    }
+}
+
+impl BakedTrails {
+    /// An empty canvas, and the entity to show it with, centered on the chamber
+    fn new(
+        entities: &Entities,
+        transforms: &mut WriteStorage<Transform>,
+        chamber: &ChamberConfig,
+        now: f32,
+    ) -> BakedTrails {
+        let scale = (MAX_TRAIL_PIXELS / (chamber.width * chamber.height))
+            .sqrt()
+            .min(1.0);
+        let width = (chamber.width * scale).round().max(1.0) as u32;
+        let height = (chamber.height * scale).round().max(1.0) as u32;
+
+        // Sprites are a world unit per pixel, so the texture is stretched back over the chamber
+        let mut transform = Transform::default();
+        transform.set_translation_xyz(chamber.width * 0.5, chamber.height * 0.5, -0.25);
+        transform.set_scale(Vector3::new(
+            chamber.width / width as f32,
+            chamber.height / height as f32,
+            1.0,
+        ));
+
+        BakedTrails {
+            canvas: Canvas::fit(width, height, chamber),
+            width,
+            height,
+            entity: entities.build_entity().with(transform, transforms).build(),
+            baked_at: now,
+            shown_at: now,
+            pending: None,
         }
     }
+
+    /// Fades the canvas as far as the trails faded since the last bake, draws `segments` onto
+    /// it as they look now, and starts loading it as a new texture
+    fn bake(
+        &mut self,
+        segments: &[Segment],
+        opacity: impl Fn(f32) -> f32,
+        loader: &Loader,
+        textures: &AssetStorage<Texture>,
+        sprite_sheets: &AssetStorage<SpriteSheet>,
+    ) -> Handle<SpriteSheet> {
+        self.canvas.fade(opacity(self.baked_at));
+        for (start, end, left_at) in segments {
+            self.canvas.stroke_polyline(
+                &[*start, *end],
+                TRAIL_WIDTH,
+                [1.0, 1.0, 1.0],
+                opacity(*left_at),
+            );
+        }
+
+        let pixels: Vec<Rgba8Srgb> = self
+            .canvas
+            .to_rgba8()
+            .into_iter()
+            .map(|repr| Rgba8Srgb { repr })
+            .collect();
+        let texture = TextureBuilder::new()
+            .with_kind(Kind::D2(self.width, self.height, 1, 1))
+            .with_view_kind(ViewKind::D2)
+            .with_data_width(self.width)
+            .with_data_height(self.height)
+            .with_data(pixels);
+        let texture = loader.load_from_data(TextureData(texture), (), textures);
+
+        // The canvas starts at the bottom of the chamber, textures at the top
+        let sprite = Sprite::from_pixel_values(
+            self.width,
+            self.height,
+            self.width,
+            self.height,
+            0,
+            0,
+            [0.0, 0.0],
+            false,
+            true,
+        );
+        loader.load_from_data(
+            SpriteSheet {
+                texture,
+                sprites: vec![sprite],
+            },
+            (),
+            sprite_sheets,
+        )
+    }
+}
+
+fn point(p: [f32; 2]) -> Point3<f32> {
+    Point3::new(p[0], p[1], 0.0)
 }